@@ -20,10 +20,16 @@
 
 //! An async process creation framework. More of a utility library.
 //!
-//! - *TODO: [`fs`] doesn't do much yet.*
+//! - [`fs`] validates and resolves paths on the local filesystem.
+//! - [`rt`] selects the async runtime used for timers and blocking work.
 //! - [`exe::Command`] covers all the configuration for a single process invocation.
+//! - [`hook`] instruments every process invocation with [`tracing`] and user-provided hooks.
+//! - [`quote`] renders command lines which can be pasted into a shell, and parses them back.
 //! - [`base::CommandBase`] abstracts a process invocation which requires setup work.
 //! - [`sync`] and [`stream`] invoke processes "synchronously" or "asynchronously".
+//! - [`pipe`] connects processes into a pipeline, which can be invoked the same way.
+//! - [`blocking`] invokes processes and pipelines from synchronous code without a runtime.
+//! - [`pty`] invokes processes attached to a pseudo-terminal.
 //! - [`sh`] wraps a shell script invocation.
 
 #![deny(rustdoc::missing_crate_level_docs)]
@@ -31,14 +37,41 @@
 /* Make all doctests fail if they produce any warnings. */
 #![doc(test(attr(deny(warnings))))]
 #![deny(clippy::all)]
+/* Errors carry the full command line which produced them, on purpose. */
+#![allow(clippy::result_large_err, clippy::large_enum_variant)]
 
 /// Representations of filesystem locations on the local host.
 ///
-/// *TODO: currently these don't do any validation!*
+/// The tuple constructors perform no validation; use [`fs::File::validate`] and
+/// [`fs::Directory::validate`] (or their `*_relative_to` variants) to check a path before it is
+/// handed to a child process.
+///```
+/// use super_process::fs;
+///
+/// let tmp = fs::Directory::validate("/tmp").expect("/tmp should be a directory");
+/// assert!(tmp.0.is_absolute());
+///
+/// match fs::File::validate("/tmp") {
+///   Err(fs::FsError::WrongKind { expected, found, .. }) => {
+///     assert_eq!(expected, fs::FileKind::File);
+///     assert_eq!(found, fs::FileKind::Directory);
+///   },
+///   _ => unreachable!(),
+/// }
+///
+/// assert!(matches!(
+///   fs::File::validate_relative_to("does-not-exist", &tmp),
+///   Err(fs::FsError::DoesNotExist(_)),
+/// ));
+///```
 pub mod fs {
   use displaydoc::Display;
+  use thiserror::Error;
 
-  use std::path::PathBuf;
+  use std::{
+    fs, io,
+    path::{Path, PathBuf},
+  };
 
   /// Trait for objects representing a handle to a filesystem path.
   pub(crate) trait PathWrapper {
@@ -46,13 +79,128 @@ pub mod fs {
     fn into_path_buf(self) -> PathBuf;
   }
 
+  /// The type of entry found at some filesystem path.
+  #[derive(Debug, Display, Copy, Clone, PartialEq, Eq)]
+  pub enum FileKind {
+    /// regular file
+    File,
+    /// directory
+    Directory,
+    /// symlink
+    Symlink,
+    /// special file
+    Other,
+  }
+
+  impl FileKind {
+    fn from_file_type(file_type: fs::FileType) -> Self {
+      if file_type.is_symlink() {
+        Self::Symlink
+      } else if file_type.is_file() {
+        Self::File
+      } else if file_type.is_dir() {
+        Self::Directory
+      } else {
+        Self::Other
+      }
+    }
+
+    /// Determine the type of entry at `path`, *without* following symlinks.
+    pub fn of_link(path: impl AsRef<Path>) -> Result<Self, FsError> {
+      let path = path.as_ref();
+      let metadata = fs::symlink_metadata(path).map_err(|e| FsError::from_io(path, e))?;
+      Ok(Self::from_file_type(metadata.file_type()))
+    }
+
+    /// Determine the type of entry at `path`, following any symlinks.
+    pub fn of(path: impl AsRef<Path>) -> Result<Self, FsError> {
+      let path = path.as_ref();
+      let metadata = fs::metadata(path).map_err(|e| FsError::from_io(path, e))?;
+      Ok(Self::from_file_type(metadata.file_type()))
+    }
+  }
+
+  /// Errors from validating filesystem paths.
+  #[derive(Debug, Display, Error)]
+  pub enum FsError {
+    /// path {0:?} does not exist
+    DoesNotExist(PathBuf),
+    /// path {path:?} was expected to be a {expected}, but was a {found}
+    WrongKind {
+      /// The path which was checked.
+      path: PathBuf,
+      /// The type of entry that was requested.
+      expected: FileKind,
+      /// The type of entry that was found (after resolving any symlinks).
+      found: FileKind,
+    },
+    /// i/o error for path {0:?}: {1}
+    Io(PathBuf, #[source] io::Error),
+  }
+
+  impl FsError {
+    fn from_io(path: &Path, e: io::Error) -> Self {
+      if e.kind() == io::ErrorKind::NotFound {
+        Self::DoesNotExist(path.to_path_buf())
+      } else {
+        Self::Io(path.to_path_buf(), e)
+      }
+    }
+  }
+
+  /// Make `path` absolute by prefixing it with `base` if it is relative, then resolve all
+  /// symlinks and `..` components.
+  pub(crate) fn canonicalize(path: &Path, base: Option<&Path>) -> Result<PathBuf, FsError> {
+    let joined = match base {
+      Some(base) if path.is_relative() => base.join(path),
+      _ => path.to_path_buf(),
+    };
+    fs::canonicalize(&joined).map_err(|e| FsError::from_io(&joined, e))
+  }
+
+  /// Resolve `path` against `base`, then check that it points to an entry of type `expected`.
+  fn validate_kind(
+    path: &Path,
+    base: Option<&Path>,
+    expected: FileKind,
+  ) -> Result<PathBuf, FsError> {
+    let resolved = canonicalize(path, base)?;
+    /* Symlinks were already followed by canonicalization. */
+    let found = FileKind::of(&resolved)?;
+    if found == expected {
+      Ok(resolved)
+    } else {
+      Err(FsError::WrongKind {
+        path: path.to_path_buf(),
+        expected,
+        found,
+      })
+    }
+  }
+
   /// @={0}
   ///
   /// A path to a file that is assumed to already exist.
-  #[derive(Debug, Display, Clone)]
+  #[derive(Debug, Display, Clone, PartialEq, Eq)]
   #[ignore_extra_doc_attributes]
   pub struct File(pub PathBuf);
 
+  impl File {
+    /// Check that `path` exists and (after following symlinks) is a regular file.
+    ///
+    /// Relative paths are resolved against the current working directory, and the result is
+    /// always canonicalized.
+    pub fn validate(path: impl AsRef<Path>) -> Result<Self, FsError> {
+      validate_kind(path.as_ref(), None, FileKind::File).map(Self)
+    }
+
+    /// Like [`Self::validate`], but resolve relative paths against `base` instead.
+    pub fn validate_relative_to(path: impl AsRef<Path>, base: &Directory) -> Result<Self, FsError> {
+      let Directory(base) = base;
+      validate_kind(path.as_ref(), Some(base), FileKind::File).map(Self)
+    }
+  }
+
   impl PathWrapper for File {
     fn into_path_buf(self) -> PathBuf {
       let Self(path) = self;
@@ -63,10 +211,26 @@ pub mod fs {
   /// @<{0}
   ///
   /// A path to a directory that is assumed to already exist.
-  #[derive(Debug, Display, Clone)]
+  #[derive(Debug, Display, Clone, PartialEq, Eq)]
   #[ignore_extra_doc_attributes]
   pub struct Directory(pub PathBuf);
 
+  impl Directory {
+    /// Check that `path` exists and (after following symlinks) is a directory.
+    ///
+    /// Relative paths are resolved against the current working directory, and the result is
+    /// always canonicalized.
+    pub fn validate(path: impl AsRef<Path>) -> Result<Self, FsError> {
+      validate_kind(path.as_ref(), None, FileKind::Directory).map(Self)
+    }
+
+    /// Like [`Self::validate`], but resolve relative paths against `base` instead.
+    pub fn validate_relative_to(path: impl AsRef<Path>, base: &Directory) -> Result<Self, FsError> {
+      let Directory(base) = base;
+      validate_kind(path.as_ref(), Some(base), FileKind::Directory).map(Self)
+    }
+  }
+
   impl PathWrapper for Directory {
     fn into_path_buf(self) -> PathBuf {
       let Self(path) = self;
@@ -75,29 +239,166 @@ pub mod fs {
   }
 }
 
+/// The async runtime used for timers and blocking work.
+///
+/// Child processes are managed with [`async_process`], which works under any executor. The few
+/// operations which need a runtime go through [`rt::Runtime`], which is implemented by `Tokio` with
+/// the `tokio` feature, by `AsyncStd` with the `async-std` feature, and otherwise by [`rt::Smol`].
+/// [`rt::Selected`] names whichever is enabled, preferring tokio if both features are.
+///
+/// With the `tokio` feature, processes must be invoked from within a tokio runtime which has the
+/// time driver enabled.
+///```
+/// # tokio_test::block_on(async {
+/// use std::time::{Duration, Instant};
+/// use super_process::rt::{self, Runtime};
+///
+/// let start = Instant::now();
+/// rt::Selected::sleep(Duration::from_millis(10)).await;
+/// assert!(start.elapsed() >= Duration::from_millis(10));
+/// assert_eq!(4, rt::Selected::unblock(|| 2 + 2).await);
+/// # }) // async
+///```
+pub mod rt {
+  use std::{future::Future, pin::Pin, time::Duration};
+
+  /// A future returned by a [`Runtime`].
+  pub type BoxFuture<T> = Pin<Box<dyn Future<Output=T>+Send>>;
+
+  /// The operations this crate needs from an async runtime.
+  pub trait Runtime {
+    /// Resolve after `duration` has elapsed.
+    fn sleep(duration: Duration) -> BoxFuture<()>;
+
+    /// Run `f` on a thread pool for blocking work, and resolve with its result.
+    fn unblock<T, F>(f: F) -> BoxFuture<T>
+    where
+      F: FnOnce() -> T+Send+'static,
+      T: Send+'static;
+  }
+
+  /// Uses the [`async_io`] reactor and [`blocking`] thread pool, which `smol` is built on.
+  ///
+  /// These run their own background threads, so they work under any executor.
+  #[derive(Debug, Clone, Copy, Default)]
+  pub struct Smol;
+
+  impl Runtime for Smol {
+    fn sleep(duration: Duration) -> BoxFuture<()> {
+      Box::pin(async move {
+        async_io::Timer::after(duration).await;
+      })
+    }
+
+    fn unblock<T, F>(f: F) -> BoxFuture<T>
+    where
+      F: FnOnce() -> T+Send+'static,
+      T: Send+'static,
+    {
+      Box::pin(::blocking::unblock(f))
+    }
+  }
+
+  /// Uses the timer and blocking thread pool of the current tokio runtime.
+  #[cfg(feature = "tokio")]
+  #[derive(Debug, Clone, Copy, Default)]
+  pub struct Tokio;
+
+  #[cfg(feature = "tokio")]
+  impl Runtime for Tokio {
+    fn sleep(duration: Duration) -> BoxFuture<()> { Box::pin(tokio::time::sleep(duration)) }
+
+    fn unblock<T, F>(f: F) -> BoxFuture<T>
+    where
+      F: FnOnce() -> T+Send+'static,
+      T: Send+'static,
+    {
+      Box::pin(async move {
+        match tokio::task::spawn_blocking(f).await {
+          Ok(result) => result,
+          Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+          Err(e) => panic!("blocking task was cancelled: {}", e),
+        }
+      })
+    }
+  }
+
+  /// Uses the timer and blocking thread pool of async-std.
+  #[cfg(feature = "async-std")]
+  #[derive(Debug, Clone, Copy, Default)]
+  pub struct AsyncStd;
+
+  #[cfg(feature = "async-std")]
+  impl Runtime for AsyncStd {
+    fn sleep(duration: Duration) -> BoxFuture<()> { Box::pin(async_std::task::sleep(duration)) }
+
+    fn unblock<T, F>(f: F) -> BoxFuture<T>
+    where
+      F: FnOnce() -> T+Send+'static,
+      T: Send+'static,
+    {
+      Box::pin(async_std::task::spawn_blocking(f))
+    }
+  }
+
+  /// The runtime selected by cargo features.
+  #[cfg(feature = "tokio")]
+  pub type Selected = Tokio;
+  /// The runtime selected by cargo features.
+  #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+  pub type Selected = AsyncStd;
+  /// The runtime selected by cargo features.
+  #[cfg(not(any(feature = "tokio", feature = "async-std")))]
+  pub type Selected = Smol;
+}
+
 /// Representations of executable files and methods to invoke them as async processes.
 pub mod exe {
-  use super::fs::{self, PathWrapper};
+  use super::{
+    fs::{self, PathWrapper},
+    hook,
+    rt::{self, Runtime},
+    sh::Interpreter,
+    sync::RawOutput,
+  };
 
+  use async_process::Child;
   use displaydoc::Display;
+  use event_listener::Event;
+  use futures_lite::future;
   use indexmap::IndexMap;
   use lazy_static::lazy_static;
   use signal_hook::consts::{signal::*, TERM_SIGNALS};
+  use tempfile::TempPath;
   use thiserror::Error;
+  use tracing::Instrument;
 
   use std::{
     collections::VecDeque,
+    env,
     ffi::{OsStr, OsString},
-    io, iter,
-    os::unix::process::ExitStatusExt,
+    fmt, fs as std_fs,
+    io::{self, IsTerminal},
+    iter,
+    ops::{Deref, DerefMut, RangeInclusive},
+    os::unix::{
+      ffi::{OsStrExt, OsStringExt},
+      fs::PermissionsExt,
+      process::{CommandExt, ExitStatusExt},
+    },
     path::{Path, PathBuf},
-    process, str,
+    process, ptr, str,
+    sync::{
+      atomic::{AtomicBool, Ordering},
+      Arc,
+    },
+    time::{Duration, Instant},
   };
 
   /// *{0}
   ///
   /// A path to an executable file which is assumed to exist.
-  #[derive(Debug, Display, Clone)]
+  #[derive(Debug, Display, Clone, PartialEq, Eq)]
   #[ignore_extra_doc_attributes]
   pub struct Exe(pub fs::File);
 
@@ -130,11 +431,123 @@ pub mod exe {
     }
   }
 
+  /// Whether `path` is a regular file (after following symlinks) with any execute bit set.
+  fn is_executable(path: &Path) -> bool {
+    std_fs::metadata(path)
+      .map(|metadata| metadata.is_file() && (metadata.permissions().mode() & 0o111) != 0)
+      .unwrap_or(false)
+  }
+
+  /// The search path which execvp(3) uses when `$PATH` is unset.
+  fn default_search_path() -> OsString {
+    let len = unsafe { libc::confstr(libc::_CS_PATH, ptr::null_mut(), 0) };
+    if len > 0 {
+      let mut buf: Vec<u8> = vec![0; len];
+      if unsafe { libc::confstr(libc::_CS_PATH, buf.as_mut_ptr().cast(), len) } == len {
+        /* Drop the trailing NUL. */
+        buf.pop();
+        return OsString::from_vec(buf);
+      }
+    }
+    OsString::from("/bin:/usr/bin")
+  }
+
+  impl Exe {
+    fn resolve_blocking(
+      self,
+      search_path: Option<OsString>,
+      wd: Option<fs::Directory>,
+    ) -> Result<Self, CommandError> {
+      let base = match wd {
+        Some(fs::Directory(wd)) => wd,
+        None => env::current_dir()?,
+      };
+      let Self(fs::File(ref exe)) = self;
+
+      /* Like execvp(3), only search $PATH if the name has no slashes. */
+      let candidates: Vec<PathBuf> = if exe.as_os_str().as_bytes().contains(&b'/') {
+        vec![base.join(exe)]
+      } else {
+        env::split_paths(&search_path.unwrap_or_else(default_search_path))
+          .map(|dir| {
+            /* An empty entry in $PATH refers to the current directory. */
+            let dir = if dir.as_os_str().is_empty() {
+              PathBuf::from(".")
+            } else {
+              dir
+            };
+            base.join(dir).join(exe)
+          })
+          .collect()
+      };
+
+      /* NB: We avoid canonicalizing the result, since multi-call binaries like busybox inspect
+       * the name they were invoked with. */
+      match candidates.iter().find(|candidate| is_executable(candidate)) {
+        Some(found) => Ok(Self(fs::File(found.clone()))),
+        None => Err(CommandError::ExeNotFound {
+          exe: self,
+          searched: candidates,
+        }),
+      }
+    }
+
+    /// Locate this executable, returning an absolute path to a file with the execute bit set.
+    ///
+    /// If this executable's name contains no slashes, each entry of `search_path` is searched
+    /// in order, as with `$PATH`. Otherwise, the name is resolved relative to `wd` (or the
+    /// current working directory if `wd` is [`None`]), as are any relative entries of
+    /// `search_path`. If `search_path` is [`None`], the system's default search path is used,
+    /// as with execvp(3).
+    ///```
+    /// # tokio_test::block_on(async {
+    /// use std::{env, path::PathBuf};
+    /// use super_process::{fs, exe};
+    ///
+    /// let sh = exe::Exe(fs::File(PathBuf::from("sh")));
+    /// let path = env::var_os("PATH");
+    /// let exe::Exe(fs::File(resolved)) = sh.clone().resolve(path, None).await.unwrap();
+    /// assert!(resolved.is_absolute());
+    /// // Without a search path, the system default is searched.
+    /// let exe::Exe(fs::File(resolved)) = sh.resolve(None, None).await.unwrap();
+    /// assert!(resolved.is_absolute());
+    ///
+    /// // A trailing slash means the name is a path, so the search path is not consulted.
+    /// let dir = exe::Exe(fs::File(PathBuf::from("sh/")));
+    /// let wd = fs::Directory(PathBuf::from("/"));
+    /// match dir.resolve(Some("/bin:/usr/bin".into()), Some(wd)).await {
+    ///   Err(exe::CommandError::ExeNotFound { searched, .. }) => {
+    ///     assert_eq!(searched, vec![PathBuf::from("/sh/")]);
+    ///   },
+    ///   _ => unreachable!(),
+    /// }
+    ///
+    /// let typo = exe::Exe(fs::File(PathBuf::from("this-command-does-not-exist")));
+    /// match typo.resolve(Some("/bin:/usr/bin".into()), None).await {
+    ///   Err(exe::CommandError::ExeNotFound { searched, .. }) => {
+    ///     assert_eq!(searched, vec![
+    ///       PathBuf::from("/bin/this-command-does-not-exist"),
+    ///       PathBuf::from("/usr/bin/this-command-does-not-exist"),
+    ///     ]);
+    ///   },
+    ///   _ => unreachable!(),
+    /// }
+    /// # }) // async
+    ///```
+    pub async fn resolve(
+      self,
+      search_path: Option<OsString>,
+      wd: Option<fs::Directory>,
+    ) -> Result<Self, CommandError> {
+      rt::Selected::unblock(move || self.resolve_blocking(search_path, wd)).await
+    }
+  }
+
   /// [{0:?}]
   ///
   /// The command line to provide to the executable. Note that the complete "argv" used by
   /// [`Command`] contains the executable path prefixed to these arguments.
-  #[derive(Debug, Display, Clone, Default)]
+  #[derive(Debug, Display, Clone, Default, PartialEq, Eq)]
   #[ignore_extra_doc_attributes]
   pub struct Argv(pub VecDeque<OsString>);
 
@@ -169,579 +582,4384 @@ pub mod exe {
     }
   }
 
+  /// A single change to make to the environment of a subprocess.
+  ///
+  /// Operations are applied in order by [`EnvModifications`].
+  #[derive(Debug, Clone, PartialEq, Eq)]
+  pub enum EnvOp {
+    /// Set a variable, overwriting any previous value.
+    Set(OsString, OsString),
+    /// Remove a variable if it was set.
+    Unset(OsString),
+    /// Remove every variable, e.g. to start from an empty environment.
+    Clear,
+    /// Remove every variable except those named in this allowlist.
+    Retain(Vec<OsString>),
+    /// Add entries to the front of a delimited list such as `$PATH`.
+    Prepend {
+      /// The variable to modify.
+      var: OsString,
+      /// The new entries, which will appear in this order.
+      entries: Vec<OsString>,
+      /// The delimiter between entries, e.g. `:`.
+      separator: OsString,
+    },
+    /// Add entries to the end of a delimited list such as `$PATH`.
+    Append {
+      /// The variable to modify.
+      var: OsString,
+      /// The new entries, which will appear in this order.
+      entries: Vec<OsString>,
+      /// The delimiter between entries, e.g. `:`.
+      separator: OsString,
+    },
+  }
+
+  impl EnvOp {
+    /// Prepend `entries` to the `:`-separated list in `var`.
+    pub fn prepend_path<R: AsRef<OsStr>, I: iter::IntoIterator<Item=R>>(
+      var: impl AsRef<OsStr>,
+      entries: I,
+    ) -> Self {
+      Self::Prepend {
+        var: var.as_ref().to_os_string(),
+        entries: entries
+          .into_iter()
+          .map(|s| s.as_ref().to_os_string())
+          .collect(),
+        separator: ":".into(),
+      }
+    }
+
+    /// Append `entries` to the `:`-separated list in `var`.
+    pub fn append_path<R: AsRef<OsStr>, I: iter::IntoIterator<Item=R>>(
+      var: impl AsRef<OsStr>,
+      entries: I,
+    ) -> Self {
+      Self::Append {
+        var: var.as_ref().to_os_string(),
+        entries: entries
+          .into_iter()
+          .map(|s| s.as_ref().to_os_string())
+          .collect(),
+        separator: ":".into(),
+      }
+    }
+
+    /// Join the non-empty elements of `parts` with `separator`.
+    fn join<'a>(parts: impl Iterator<Item=&'a OsStr>, separator: &OsStr) -> OsString {
+      let mut joined = OsString::new();
+      for (i, part) in parts.filter(|s| !s.is_empty()).enumerate() {
+        if i > 0 {
+          joined.push(separator);
+        }
+        joined.push(part);
+      }
+      joined
+    }
+
+    /// Apply this operation to the environment `env`.
+    fn apply(&self, env: &mut IndexMap<OsString, OsString>) {
+      match self {
+        Self::Set(var, val) => {
+          env.insert(var.clone(), val.clone());
+        },
+        Self::Unset(var) => {
+          env.shift_remove(var);
+        },
+        Self::Clear => {
+          env.clear();
+        },
+        Self::Retain(allowed) => {
+          env.retain(|var, _| allowed.contains(var));
+        },
+        Self::Prepend {
+          var,
+          entries,
+          separator,
+        } => {
+          let existing = env.get(var).map(|s| s.as_os_str());
+          let joined = Self::join(
+            entries.iter().map(|s| s.as_os_str()).chain(existing),
+            separator,
+          );
+          env.insert(var.clone(), joined);
+        },
+        Self::Append {
+          var,
+          entries,
+          separator,
+        } => {
+          let existing = env.get(var).map(|s| s.as_os_str());
+          let joined = Self::join(
+            existing
+              .into_iter()
+              .chain(entries.iter().map(|s| s.as_os_str())),
+            separator,
+          );
+          env.insert(var.clone(), joined);
+        },
+      }
+    }
+  }
+
   /// [{0:?}]
   ///
-  /// Environment variables to set in the subprocess environment.
+  /// An ordered sequence of changes to the inherited environment of a subprocess.
+  ///```
+  /// use std::ffi::OsString;
+  /// use super_process::exe::{EnvModifications, EnvOp};
+  ///
+  /// let parent: Vec<(OsString, OsString)> = vec![
+  ///   ("PATH".into(), "/usr/bin".into()),
+  ///   ("HOME".into(), "/home/me".into()),
+  ///   ("SECRET".into(), "xxx".into()),
+  /// ];
+  ///
+  /// let hermetic = EnvModifications(vec![
+  ///   EnvOp::Retain(vec!["PATH".into(), "HOME".into()]),
+  ///   EnvOp::prepend_path("PATH", ["/opt/bin"]),
+  /// ]);
+  /// let extra = EnvModifications::from([("CC", "clang")])
+  ///   .merge(EnvModifications(vec![EnvOp::Unset("HOME".into())]));
+  ///
+  /// let env = hermetic.merge(extra).resolve(parent);
+  /// assert_eq!(
+  ///   env.into_iter().collect::<Vec<_>>(),
+  ///   vec![
+  ///     ("PATH".into(), "/opt/bin:/usr/bin".into()),
+  ///     ("CC".into(), "clang".into()),
+  ///   ],
+  /// );
+  ///```
   #[derive(Debug, Display, Clone, Default)]
   #[ignore_extra_doc_attributes]
-  pub struct EnvModifications(pub IndexMap<OsString, OsString>);
+  pub struct EnvModifications(pub Vec<EnvOp>);
 
   impl<R: AsRef<OsStr>, I: iter::IntoIterator<Item=(R, R)>> From<I> for EnvModifications {
     fn from(value: I) -> Self {
-      let env: IndexMap<OsString, OsString> = value
+      let env: Vec<EnvOp> = value
         .into_iter()
         .map(|(k, v)| {
           let k: &OsStr = k.as_ref();
           let v: &OsStr = v.as_ref();
-          (k.to_os_string(), v.to_os_string())
+          EnvOp::Set(k.to_os_string(), v.to_os_string())
         })
         .collect();
       Self(env)
     }
   }
 
-  /// <exe={exe}, wd={wd:?}, argv={argv}, env={env}>
-  ///
-  /// Request to execute a subprocess. See [`crate::sync`] and [`crate::stream`] for examples
-  /// of invocation.
-  #[derive(Debug, Display, Clone, Default)]
-  #[ignore_extra_doc_attributes]
-  pub struct Command {
-    /// Executable name, which may be absolute or relative to `$PATH` entries.
-    pub exe: Exe,
-    /// The working directory for the child process; otherwise, the working directory is inherited
-    /// from the parent process.
-    pub wd: Option<fs::Directory>,
-    /// Arguments to pass to the executable. These should *not* be quoted at all.
-    pub argv: Argv,
-    /// Any new environment variables to set within the child process. The environment is
-    /// otherwise inherited from the parent.
-    pub env: EnvModifications,
-  }
+  impl EnvModifications {
+    /// Whether the child process would receive exactly the parent's environment.
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
 
-  impl Command {
-    pub(crate) fn command(self) -> async_process::Command {
-      dbg!(&self);
-      let Self {
-        exe,
-        wd,
-        argv,
-        env: EnvModifications(env),
-      } = self;
-      if exe.is_empty() {
-        unreachable!(
-          "command was executed before .exe was set; this can only occur using ::default()"
-        );
-      }
-      let mut command = async_process::Command::new(exe.into_path_buf());
-      if let Some(wd) = wd {
-        command.current_dir(wd.into_path_buf());
-      }
-      command.args(argv.0);
-      for (var, val) in env.into_iter() {
-        command.env(&var, &val);
-      }
-      command
-    }
+    /// Append a single operation.
+    pub fn push(&mut self, op: EnvOp) { self.0.push(op); }
 
-    /// Make this command execute the `new_exe` binary instead, shifting all args one to the right.
-    pub fn unshift_new_exe(&mut self, new_exe: Exe) {
-      if new_exe.is_empty() {
-        unreachable!("new_exe is an empty string!! self was: {:?}", self);
-      }
+    /// Produce a set of modifications which applies `self`, then `other`.
+    pub fn merge(self, other: Self) -> Self {
+      let Self(mut ops) = self;
+      let Self(other) = other;
+      ops.extend(other);
+      Self(ops)
+    }
 
-      let mut argv = self.argv.clone();
-      if !self.exe.is_empty() {
-        argv.unshift(self.exe.clone().into_path_buf().as_os_str().to_os_string());
+    /// Apply these modifications to the environment `parent`, in order.
+    pub fn resolve<K: Into<OsString>, V: Into<OsString>, I: iter::IntoIterator<Item=(K, V)>>(
+      &self,
+      parent: I,
+    ) -> IndexMap<OsString, OsString> {
+      let mut env: IndexMap<OsString, OsString> = parent
+        .into_iter()
+        .map(|(k, v)| (k.into(), v.into()))
+        .collect();
+      for op in self.0.iter() {
+        op.apply(&mut env);
       }
-
-      self.argv = argv;
-      self.exe = new_exe;
+      env
     }
 
-    pub(crate) fn unshift_shell_script(&mut self, script_path: Exe) {
-      self.unshift_new_exe(script_path);
-      self.unshift_new_exe(Exe(fs::File(PathBuf::from("sh"))));
+    /// The variables which would be set if these modifications were applied to an empty
+    /// environment.
+    pub fn bindings(&self) -> IndexMap<OsString, OsString> {
+      self.resolve(iter::empty::<(OsString, OsString)>())
     }
-  }
-
-  /// Errors that can occur when executing command lines.
-  #[derive(Debug, Display, Error)]
-  pub enum CommandError {
-    /// a command line exited with non-zero status {0}
-    NonZeroExit(i32),
-    /// a command line exited with termination signal {0} ({1})
-    ProcessTerminated(i32, &'static str),
-    /// a command line exited with non-termination signal {0} ({1})
-    ProcessKilled(i32, &'static str),
-    /// i/o error invoking command line: {0}
-    Io(#[from] io::Error),
-    /// utf-8 decoding error for command line: {0}
-    Utf8(#[from] str::Utf8Error),
-  }
 
-  macro_rules! signal_pairs {
-  ($($name:ident),+) => {
-    [$(($name, stringify!($name))),+]
-  }
+    /// The value `var` would have in the child process, if it had the value `initial` in the
+    /// parent.
+    pub fn resolve_var(&self, var: &OsStr, initial: Option<OsString>) -> Option<OsString> {
+      let parent = initial.map(|val| (var.to_os_string(), val));
+      self.resolve(parent).swap_remove(var)
+    }
   }
 
-  lazy_static! {
-    static ref SIGNAL_NAMES: IndexMap<i32, &'static str> = signal_pairs![
-      SIGABRT, SIGALRM, SIGBUS, SIGCHLD, SIGCONT, SIGFPE, SIGHUP, SIGILL, SIGINT, SIGIO, SIGKILL,
-      SIGPIPE, SIGPROF, SIGQUIT, SIGSEGV, SIGSTOP, SIGSYS, SIGTERM, SIGTRAP, SIGTSTP, SIGTTIN,
-      SIGTTOU, SIGURG, SIGUSR1, SIGUSR2, SIGVTALRM, SIGWINCH, SIGXCPU, SIGXFSZ
-    ]
-    .into();
+  /// The differences between two snapshots of an environment, such as before and after a shell
+  /// script ran in
+  /// [`EnvAfterScript::extract_env_diff`](crate::sh::EnvAfterScript::extract_env_diff).
+  ///
+  /// This converts into [`EnvModifications`], to replay the changes onto other commands.
+  #[derive(Debug, Clone, Default, PartialEq, Eq)]
+  pub struct EnvDiff {
+    /// Variables which were not set before.
+    pub added: IndexMap<OsString, OsString>,
+    /// Variables which were set to a different value, with their new values.
+    pub changed: IndexMap<OsString, OsString>,
+    /// Variables which were unset.
+    pub unset: Vec<OsString>,
   }
 
-  impl CommandError {
-    /// Raise an error if the process exited with any type of failure.
-    pub fn analyze_exit_status(status: process::ExitStatus) -> Result<(), Self> {
-      if let Some(code) = status.code() {
-        if code == 0 {
-          Ok(())
-        } else {
-          Err(Self::NonZeroExit(code))
+  impl EnvDiff {
+    /// Compare two snapshots of an environment.
+    pub fn between(
+      before: &IndexMap<OsString, OsString>,
+      after: &IndexMap<OsString, OsString>,
+    ) -> Self {
+      let mut diff = Self::default();
+      for (key, value) in after.iter() {
+        match before.get(key) {
+          None => {
+            diff.added.insert(key.clone(), value.clone());
+          },
+          Some(old_value) if old_value != value => {
+            diff.changed.insert(key.clone(), value.clone());
+          },
+          Some(_) => (),
         }
-      } else if let Some(signal) = status.signal() {
-        Err(if TERM_SIGNALS.contains(&signal) {
-          Self::ProcessTerminated(signal, SIGNAL_NAMES.get(&signal).unwrap())
-        } else {
-          Self::ProcessKilled(signal, SIGNAL_NAMES.get(&signal).unwrap())
-        })
-      } else {
-        unreachable!("status {:?} had no exit code or signal", status)
       }
+      diff.unset = before
+        .keys()
+        .filter(|key| !after.contains_key(*key))
+        .cloned()
+        .collect();
+      diff
     }
 
-    pub(crate) fn command_with_context(
-      self,
-      command: Command,
-      context: String,
-    ) -> CommandErrorWrapper {
-      CommandErrorWrapper {
-        command,
-        context,
-        error: self,
-      }
+    /// Whether the environment was unchanged.
+    pub fn is_empty(&self) -> bool {
+      self.added.is_empty() && self.changed.is_empty() && self.unset.is_empty()
     }
   }
 
-  /// command {command:?} failed ({context}): {error}
-  #[derive(Debug, Display, Error)]
-  pub struct CommandErrorWrapper {
-    /// The command that attempted to be executed.
-    pub command: Command,
-    /// Additional information about where the error occurred.
-    pub context: String,
-    /// The underlying error.
-    #[source]
-    pub error: CommandError,
+  impl From<EnvDiff> for EnvModifications {
+    fn from(diff: EnvDiff) -> Self {
+      let EnvDiff {
+        added,
+        changed,
+        unset,
+      } = diff;
+      let unset = unset.into_iter().map(EnvOp::Unset);
+      let set = added
+        .into_iter()
+        .chain(changed)
+        .map(|(key, value)| EnvOp::Set(key, value));
+      Self(unset.chain(set).collect())
+    }
+  }
+
+  /// Where a child process should read its standard input from.
+  ///
+  /// Arbitrary readers may also be provided with
+  /// [`SyncInvocable::invoke_with_stdin`](crate::sync::SyncInvocable::invoke_with_stdin).
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::path::PathBuf;
+  /// use futures_lite::io::Cursor;
+  /// use super_process::{fs, exe, sync::SyncInvocable};
+  ///
+  /// let command = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("cat"))),
+  ///   stdin: Some(exe::Stdin::Bytes(b"hey\n".to_vec())),
+  ///   ..Default::default()
+  /// };
+  /// let output = command.clone().invoke().await.expect("cat should succeed");
+  /// assert_eq!(b"hey\n".as_ref(), &output.stdout);
+  ///
+  /// // Large inputs are written while the output is read, so the pipes can't fill up.
+  /// let input = vec![b'a'; 4 * 1024 * 1024];
+  /// let output = command.invoke_with_stdin(Cursor::new(input.clone())).await.unwrap();
+  /// assert_eq!(input, output.stdout);
+  /// # }) // async
+  ///```
+  #[derive(Debug, Clone)]
+  pub enum Stdin {
+    /// Share the parent process's stdin.
+    Inherit,
+    /// Read from `/dev/null`.
+    Null,
+    /// Write these bytes to the child's stdin, then close it.
+    Bytes(Vec<u8>),
+    /// Read from the given file.
+    File(fs::File),
+    /// Connect the child's stdin to a pipe. [`crate::stream`] invocations can write to it via
+    /// [`Streaming::stdin`](crate::stream::Streaming::stdin); otherwise, it is closed
+    /// immediately.
+    Piped,
+  }
+
+  #[derive(Debug, Default)]
+  struct CancellationState {
+    cancelled: AtomicBool,
+    event: Event,
+  }
+
+  /// A handle which can be used to tear down one or more running child processes.
+  ///
+  /// Clones of this token share the same state, so cancelling any clone cancels them all.
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::path::PathBuf;
+  /// use super_process::{fs, exe, stream::Streamable};
+  ///
+  /// let cancel = exe::CancellationToken::new();
+  /// let command = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("sleep"))),
+  ///   argv: ["30"].as_ref().into(),
+  ///   termination: exe::Termination {
+  ///     cancel: Some(cancel.clone()),
+  ///     ..Default::default()
+  ///   },
+  ///   ..Default::default()
+  /// };
+  /// let streaming = command.invoke_streaming().unwrap();
+  /// cancel.cancel();
+  /// let e = streaming.wait().await.unwrap_err();
+  /// assert!(matches!(e.error, exe::CommandError::Cancelled { .. }));
+  /// # }) // async
+  ///```
+  #[derive(Debug, Clone, Default)]
+  pub struct CancellationToken(Arc<CancellationState>);
+
+  impl CancellationToken {
+    /// Create a new token which has not been cancelled.
+    pub fn new() -> Self { Self::default() }
+
+    /// Tear down every child process invoked with this token.
+    pub fn cancel(&self) {
+      let Self(state) = self;
+      state.cancelled.store(true, Ordering::SeqCst);
+      state.event.notify(usize::MAX);
+    }
+
+    /// Whether [`Self::cancel`] has been called on this token or any of its clones.
+    pub fn is_cancelled(&self) -> bool {
+      let Self(state) = self;
+      state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Wait until [`Self::cancel`] is called.
+    pub async fn cancelled(&self) {
+      let Self(state) = self;
+      loop {
+        if self.is_cancelled() {
+          return;
+        }
+        /* Check again after registering the listener to avoid missing a notification. */
+        let listener = state.event.listen();
+        if self.is_cancelled() {
+          return;
+        }
+        listener.await;
+      }
+    }
+  }
+
+  /// The reason a child process was torn down before it exited on its own.
+  #[derive(Debug, Copy, Clone)]
+  pub(crate) enum Interrupt {
+    TimedOut(Duration),
+    Cancelled,
+  }
+
+  impl Interrupt {
+    pub(crate) fn into_error(self, output: RawOutput) -> CommandError {
+      match self {
+        Self::TimedOut(after) => CommandError::TimedOut { after, output },
+        Self::Cancelled => CommandError::Cancelled { output },
+      }
+    }
+  }
+
+  /// Send `signal` to `target`, ignoring processes which have already exited.
+  ///
+  /// As with `kill(2)`, a negative `target` signals every process in that process group.
+  fn send_signal(target: libc::pid_t, signal: i32) -> io::Result<()> {
+    if unsafe { libc::kill(target, signal) } == 0 {
+      return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() == Some(libc::ESRCH) {
+      Ok(())
+    } else {
+      Err(e)
+    }
+  }
+
+  /// Send `signal` to the process `pid`, or to its entire process group if it leads one.
+  pub(crate) fn signal_process(pid: u32, leads_group: bool, signal: i32) -> io::Result<()> {
+    let pid = libc::pid_t::try_from(pid).expect("pid should fit in pid_t");
+    if leads_group {
+      send_signal(-pid, signal)
+    } else {
+      send_signal(pid, signal)
+    }
+  }
+
+  /// Which process group and session a child process should belong to.
+  #[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+  pub enum ProcessGroup {
+    /// Stay in the parent's process group.
+    #[default]
+    Inherit,
+    /// Lead a new process group, so the child and all of its descendants can be signalled at
+    /// once.
+    NewGroup,
+    /// Lead a new session (and process group), which also detaches the child from any
+    /// controlling terminal.
+    NewSession,
+  }
+
+  impl ProcessGroup {
+    fn configure(self, command: &mut process::Command) {
+      match self {
+        Self::Inherit => (),
+        Self::NewGroup => unsafe {
+          command.pre_exec(|| {
+            if libc::setpgid(0, 0) == 0 {
+              Ok(())
+            } else {
+              Err(io::Error::last_os_error())
+            }
+          });
+        },
+        Self::NewSession => unsafe {
+          command.pre_exec(|| {
+            if libc::setsid() == -1 {
+              Err(io::Error::last_os_error())
+            } else {
+              Ok(())
+            }
+          });
+        },
+      }
+    }
+  }
+
+  /// A handle to a live child process.
+  ///
+  /// If [`Termination::kill_on_drop`] was set, dropping this handle sends `SIGKILL` to the child,
+  /// or to its entire process group if [`Command::process_group`] created one. This also kills
+  /// any descendants left running in the group after the child itself has exited.
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::{path::PathBuf, process, thread, time::Duration};
+  /// use futures_lite::io::{AsyncBufReadExt, BufReader};
+  /// use super_process::{fs, exe, stream::Streamable};
+  ///
+  /// // Start a background job, and report its pid.
+  /// let command = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+  ///   argv: ["-c", "sleep 30 & echo $!; wait"].as_ref().into(),
+  ///   process_group: exe::ProcessGroup::NewGroup,
+  ///   termination: exe::Termination {
+  ///     kill_on_drop: true,
+  ///     ..Default::default()
+  ///   },
+  ///   ..Default::default()
+  /// };
+  /// let mut streaming = command.invoke_streaming().unwrap();
+  /// let mut pid = String::new();
+  /// BufReader::new(&mut streaming.stdout).read_line(&mut pid).await.unwrap();
+  ///
+  /// // Dropping the handle kills the whole process group, including the background job.
+  /// drop(streaming);
+  /// let is_dead = || {
+  ///   let ps = process::Command::new("ps")
+  ///     .args(["-o", "stat=", "-p", pid.trim()])
+  ///     .output()
+  ///     .unwrap();
+  ///   /* The job may linger as a zombie until it is reaped by init. */
+  ///   !ps.status.success() || ps.stdout.starts_with(b"Z")
+  /// };
+  /// for _ in 0..100 {
+  ///   if is_dead() {
+  ///     break;
+  ///   }
+  ///   thread::sleep(Duration::from_millis(20));
+  /// }
+  /// assert!(is_dead());
+  /// # }) // async
+  ///```
+  #[derive(Debug)]
+  pub struct ManagedChild {
+    child: Child,
+    command: Arc<Command>,
+    leads_group: bool,
+    kill_on_drop: bool,
+    started: Instant,
+    span: tracing::Span,
+    output: Option<hook::OutputBytes>,
+    exited: Option<Duration>,
+  }
+
+  impl ManagedChild {
+    pub(crate) fn spawn(
+      command: &mut async_process::Command,
+      config: Arc<Command>,
+    ) -> io::Result<Self> {
+      let child = command.spawn()?;
+      let span = hook::spawned(&config, child.id());
+      Ok(Self {
+        child,
+        leads_group: config.process_group != ProcessGroup::Inherit,
+        kill_on_drop: config.termination.kill_on_drop,
+        command: config,
+        started: Instant::now(),
+        span,
+        output: None,
+        exited: None,
+      })
+    }
+
+    /// The time from when the child was spawned until [`Self::wait`] first saw it exit, or until
+    /// now if it has not been seen to exit.
+    pub fn elapsed(&self) -> Duration { self.exited.unwrap_or_else(|| self.started.elapsed()) }
+
+    /// Whether [`Self::wait`] has seen the child exit.
+    pub fn has_exited(&self) -> bool { self.exited.is_some() }
+
+    /// The [`tracing`] span which covers the lifetime of this child. See [`crate::hook`].
+    pub fn span(&self) -> &tracing::Span { &self.span }
+
+    /// Record how much output was read from the child, to be reported when it exits.
+    pub(crate) fn record_output(&mut self, output: hook::OutputBytes) {
+      self.output = Some(output);
+    }
+
+    /// Wait for the child to exit, reporting its exit status to [`crate::hook`] the first time.
+    pub async fn wait(&mut self) -> io::Result<process::ExitStatus> {
+      let status = self
+        .child
+        .status()
+        .instrument(self.span.clone())
+        .await?;
+      if self.exited.is_none() {
+        let elapsed = self.started.elapsed();
+        self.exited = Some(elapsed);
+        hook::exited(&self.span, &hook::ExitEvent {
+          command: &self.command,
+          pid: self.child.id(),
+          status,
+          elapsed,
+          output: self.output,
+        });
+      }
+      Ok(status)
+    }
+
+    /// Send `signal` to the child, or to its entire process group if it leads one.
+    pub fn signal(&self, signal: i32) -> io::Result<()> {
+      signal_process(self.child.id(), self.leads_group, signal)
+    }
+
+    /// Like [`Self::signal`], but do nothing if the child has been reaped and does not lead a
+    /// process group.
+    pub(crate) fn signal_if_running(&mut self, signal: i32) -> io::Result<()> {
+      /* If the child has been reaped, its pid may have been reused, so only signal it if it's
+       * still running. A process group remains reserved as long as any member is alive. */
+      if self.leads_group || matches!(self.child.try_status(), Ok(None)) {
+        self.signal(signal)?;
+      }
+      Ok(())
+    }
+  }
+
+  impl Deref for ManagedChild {
+    type Target = Child;
+
+    fn deref(&self) -> &Child { &self.child }
+  }
+
+  impl DerefMut for ManagedChild {
+    fn deref_mut(&mut self) -> &mut Child { &mut self.child }
+  }
+
+  impl Drop for ManagedChild {
+    fn drop(&mut self) {
+      if self.kill_on_drop {
+        let _ = self.signal_if_running(SIGKILL);
+      }
+    }
+  }
+
+  /// When and how to tear down a child process which has not exited by itself.
+  ///
+  /// Teardown first sends `SIGTERM`, then waits for [`Self::grace_period`] before sending
+  /// `SIGKILL`.
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::{path::PathBuf, time::Duration};
+  /// use super_process::{fs, exe, sync::SyncInvocable};
+  ///
+  /// let command = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+  ///   argv: ["-c", "echo started; sleep 30"].as_ref().into(),
+  ///   termination: exe::Termination {
+  ///     timeout: Some(Duration::from_millis(500)),
+  ///     ..Default::default()
+  ///   },
+  ///   ..Default::default()
+  /// };
+  /// match command.invoke().await {
+  ///   Err(exe::CommandErrorWrapper {
+  ///     error: exe::CommandError::TimedOut { output, .. },
+  ///     ..
+  ///   }) => assert_eq!(b"started\n".as_ref(), &output.stdout),
+  ///   _ => unreachable!(),
+  /// }
+  /// # }) // async
+  ///```
+  #[derive(Debug, Clone)]
+  pub struct Termination {
+    /// How long the child may run before it is torn down.
+    pub timeout: Option<Duration>,
+    /// A token which tears down the child when cancelled.
+    pub cancel: Option<CancellationToken>,
+    /// How long to wait after sending `SIGTERM` before sending `SIGKILL`.
+    pub grace_period: Duration,
+    /// Whether to send `SIGKILL` when the handle to the child is dropped. See [`ManagedChild`].
+    pub kill_on_drop: bool,
+  }
+
+  impl Termination {
+    /// The default value for [`Self::grace_period`].
+    pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+    /// Resolve when the child process should be torn down, or never if no timeout or
+    /// cancellation token was provided.
+    pub(crate) async fn interrupted(&self) -> Interrupt {
+      let timed_out = async {
+        match self.timeout {
+          Some(after) => {
+            rt::Selected::sleep(after).await;
+            Interrupt::TimedOut(after)
+          },
+          None => future::pending().await,
+        }
+      };
+      let cancelled = async {
+        match self.cancel {
+          Some(ref cancel) => {
+            cancel.cancelled().await;
+            Interrupt::Cancelled
+          },
+          None => future::pending().await,
+        }
+      };
+      future::or(cancelled, timed_out).await
+    }
+
+    /// Send `SIGTERM` to `child`, then `SIGKILL` if it has not exited after the grace period.
+    ///
+    /// If `child` leads a process group, the whole group is signalled. If `child` has already been
+    /// reaped and does not lead a group, it is not signalled at all.
+    pub(crate) async fn terminate(
+      &self,
+      child: &mut ManagedChild,
+    ) -> io::Result<process::ExitStatus> {
+      child.signal_if_running(SIGTERM)?;
+      let exited = async { Some(child.wait().await) };
+      let grace_period_elapsed = async {
+        rt::Selected::sleep(self.grace_period).await;
+        None
+      };
+      if let Some(status) = future::or(exited, grace_period_elapsed).await {
+        return status;
+      }
+      child.signal_if_running(SIGKILL)?;
+      child.wait().await
+    }
+  }
+
+  impl Default for Termination {
+    fn default() -> Self {
+      Self {
+        timeout: None,
+        cancel: None,
+        grace_period: Self::DEFAULT_GRACE_PERIOD,
+        kill_on_drop: false,
+      }
+    }
+  }
+
+  /// Which exit statuses count as success for a child process.
+  ///
+  /// By default, only an exit code of 0 is accepted, as with
+  /// [`CommandError::analyze_exit_status`]. The accepted status is returned in
+  /// [`RawOutput::status`], or as [`crate::stream::StdioEvent::Exit`] when streaming.
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::{os::unix::process::ExitStatusExt, path::PathBuf};
+  /// use super_process::{fs, exe, sync::SyncInvocable};
+  ///
+  /// // grep exits with 1 when nothing matched, and 2 on error.
+  /// let grep = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("grep"))),
+  ///   argv: ["needle"].as_ref().into(),
+  ///   stdin: Some(exe::Stdin::Bytes(b"haystack\n".to_vec())),
+  ///   exit_policy: exe::ExitPolicy::codes([0, 1]),
+  ///   ..Default::default()
+  /// };
+  /// let output = grep.invoke().await.unwrap();
+  /// assert_eq!(Some(1), output.status.and_then(|status| status.code()));
+  /// assert!(output.stdout.is_empty());
+  ///
+  /// // Codes outside the accepted ranges still fail.
+  /// let rsync = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+  ///   argv: ["-c", "exit 23"].as_ref().into(),
+  ///   exit_policy: exe::ExitPolicy::ranges([0..=0, 24..=24]),
+  ///   ..Default::default()
+  /// };
+  /// match rsync.invoke().await {
+  ///   Err(exe::CommandErrorWrapper {
+  ///     error: exe::CommandError::NonZeroExit(23),
+  ///     ..
+  ///   }) => (),
+  ///   _ => unreachable!(),
+  /// }
+  ///
+  /// // A predicate can also accept signals.
+  /// let command = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+  ///   argv: ["-c", "kill $$"].as_ref().into(),
+  ///   exit_policy: exe::ExitPolicy::predicate(|status| status.signal() == Some(15)),
+  ///   ..Default::default()
+  /// };
+  /// let output = command.invoke().await.unwrap();
+  /// assert_eq!(Some(15), output.status.and_then(|status| status.signal()));
+  /// # }) // async
+  ///```
+  #[derive(Clone, Default)]
+  pub enum ExitPolicy {
+    /// Accept only an exit code of 0.
+    #[default]
+    Success,
+    /// Accept any exit code within these ranges. A process killed by a signal is rejected.
+    Codes(Vec<RangeInclusive<i32>>),
+    /// Accept any status for which this returns `true`.
+    Predicate(Arc<dyn Fn(process::ExitStatus) -> bool+Send+Sync>),
+  }
+
+  impl fmt::Debug for ExitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      match self {
+        Self::Success => write!(f, "Success"),
+        Self::Codes(ranges) => f.debug_tuple("Codes").field(ranges).finish(),
+        Self::Predicate(_) => write!(f, "Predicate(..)"),
+      }
+    }
+  }
+
+  impl ExitPolicy {
+    /// Accept exactly these exit codes. Include 0 if it should still be accepted.
+    pub fn codes<I: iter::IntoIterator<Item=i32>>(codes: I) -> Self {
+      Self::Codes(codes.into_iter().map(|code| code..=code).collect())
+    }
+
+    /// Accept any exit code within these ranges.
+    pub fn ranges<I: iter::IntoIterator<Item=RangeInclusive<i32>>>(ranges: I) -> Self {
+      Self::Codes(ranges.into_iter().collect())
+    }
+
+    /// Accept any status for which `f` returns `true`.
+    pub fn predicate<F>(f: F) -> Self
+    where F: Fn(process::ExitStatus) -> bool+Send+Sync+'static {
+      Self::Predicate(Arc::new(f))
+    }
+
+    /// Whether `status` counts as success.
+    pub fn accepts(&self, status: process::ExitStatus) -> bool {
+      match self {
+        Self::Success => status.success(),
+        Self::Codes(ranges) => status
+          .code()
+          .is_some_and(|code| ranges.iter().any(|range| range.contains(&code))),
+        Self::Predicate(f) => f(status),
+      }
+    }
+
+    /// Raise an error if `status` is not accepted, describing it as in
+    /// [`CommandError::analyze_exit_status`].
+    pub fn check(&self, status: process::ExitStatus) -> Result<(), CommandError> {
+      if self.accepts(status) {
+        return Ok(());
+      }
+      CommandError::analyze_exit_status(status)?;
+      Err(CommandError::ExitRejected(status))
+    }
+  }
+
+  /// <exe={exe}, wd={wd:?}, argv={argv}, env={env}>
+  ///
+  /// Request to execute a subprocess. See [`crate::sync`] and [`crate::stream`] for examples
+  /// of invocation.
+  #[derive(Debug, Display, Clone, Default)]
+  #[ignore_extra_doc_attributes]
+  pub struct Command {
+    /// Executable name, which may be absolute or relative to `$PATH` entries.
+    pub exe: Exe,
+    /// The working directory for the child process; otherwise, the working directory is inherited
+    /// from the parent process.
+    pub wd: Option<fs::Directory>,
+    /// Arguments to pass to the executable. These should *not* be quoted at all.
+    pub argv: Argv,
+    /// Modifications to make to the environment of the child process. The environment is
+    /// otherwise inherited from the parent.
+    pub env: EnvModifications,
+    /// Where the child process reads its input from; otherwise, [`crate::sync`] invocations
+    /// read from `/dev/null` and [`crate::stream`] invocations inherit the parent's stdin.
+    pub stdin: Option<Stdin>,
+    /// When to tear down the child process if it has not exited by itself.
+    pub termination: Termination,
+    /// Which exit statuses count as success.
+    pub exit_policy: ExitPolicy,
+    /// Whether to spawn the child in a new process group or session.
+    pub process_group: ProcessGroup,
+    /// Temporary files which the child process depends on, such as a generated shell script.
+    /// They are deleted when the last clone of this command is dropped.
+    pub temp_files: Vec<Arc<TempPath>>,
+  }
+
+  impl Command {
+    pub(crate) fn command(&self) -> Result<async_process::Command, CommandErrorWrapper> {
+      let (command, stdin) = self.std_command()?;
+      let mut command = async_process::Command::from(command);
+      if let Some(stdin) = stdin {
+        command.stdin(stdin);
+      }
+      Ok(command)
+    }
+
+    /// Configure a [`process::Command`], along with its stdin if [`Self::stdin`] was provided.
+    ///
+    /// The stdin is returned separately, since [`async_process::Command`] resets it otherwise.
+    pub(crate) fn std_command(
+      &self,
+    ) -> Result<(process::Command, Option<process::Stdio>), CommandErrorWrapper> {
+      let Self {
+        exe,
+        wd,
+        argv,
+        env,
+        stdin,
+        termination: _,
+        exit_policy: _,
+        process_group,
+        temp_files: _,
+      } = self;
+      if exe.is_empty() {
+        unreachable!(
+          "command was executed before .exe was set; this can only occur using ::default()"
+        );
+      }
+      let mut command = process::Command::new(exe.clone().into_path_buf());
+      process_group.configure(&mut command);
+      if let Some(wd) = wd {
+        /* Check the working directory now, since a nonexistent cwd would otherwise be reported
+         * as an ENOENT which is indistinguishable from a missing executable. */
+        let wd = fs::Directory::validate(wd.clone().into_path_buf())
+          .map_err(|e| e.into())
+          .map_err(|e: CommandError| {
+            e.command_with_context(self.clone(), "validating working directory".to_string())
+          })?;
+        command.current_dir(wd.into_path_buf());
+      }
+      command.args(argv.0.iter());
+      if !env.is_empty() {
+        command.env_clear();
+        command.envs(env.resolve(env::vars_os()));
+      }
+      let stdin = match stdin {
+        None => None,
+        Some(Stdin::Inherit) => Some(process::Stdio::inherit()),
+        Some(Stdin::Null) => Some(process::Stdio::null()),
+        Some(Stdin::Bytes(_) | Stdin::Piped) => Some(process::Stdio::piped()),
+        Some(Stdin::File(file)) => {
+          let file = std_fs::File::open(file.clone().into_path_buf())
+            .map_err(|e| e.into())
+            .map_err(|e: CommandError| {
+              e.command_with_context(self.clone(), "opening file for stdin".to_string())
+            })?;
+          Some(file.into())
+        },
+      };
+      Ok((command, stdin))
+    }
+
+    /// Search for [`Self::exe`] and replace it with the absolute path to the executable.
+    ///
+    /// If [`Self::env`] sets `$PATH`, that value is searched instead of the parent process's
+    /// `$PATH`. See [`Exe::resolve`].
+    pub async fn resolve_exe(mut self) -> Result<Self, CommandErrorWrapper> {
+      let search_path = self
+        .env
+        .resolve_var(OsStr::new("PATH"), env::var_os("PATH"));
+      match self.exe.clone().resolve(search_path, self.wd.clone()).await {
+        Ok(exe) => {
+          self.exe = exe;
+          Ok(self)
+        },
+        Err(e) => Err(hook::failed(
+          e.command_with_context(self, "resolving executable".to_string()),
+        )),
+      }
+    }
+
+    /// Make this command execute the `new_exe` binary instead, shifting all args one to the right.
+    pub fn unshift_new_exe(&mut self, new_exe: Exe) {
+      if new_exe.is_empty() {
+        unreachable!("new_exe is an empty string!! self was: {:?}", self);
+      }
+
+      let mut argv = self.argv.clone();
+      if !self.exe.is_empty() {
+        argv.unshift(self.exe.clone().into_path_buf().as_os_str().to_os_string());
+      }
+
+      self.argv = argv;
+      self.exe = new_exe;
+    }
+
+    /// Make this command execute `interpreter` with `script_args`, which are followed by the
+    /// previous command line.
+    pub(crate) fn unshift_shell_script(
+      &mut self,
+      interpreter: Interpreter,
+      script_args: Vec<OsString>,
+    ) {
+      let Interpreter { exe, flags } = interpreter;
+      self.unshift_new_exe(exe);
+      for arg in flags.0.into_iter().chain(script_args).rev() {
+        self.argv.unshift(arg);
+      }
+    }
+  }
+
+  /// Errors that can occur when executing command lines.
+  #[derive(Debug, Display, Error)]
+  pub enum CommandError {
+    /// a command line exited with non-zero status {0}
+    NonZeroExit(i32),
+    /// a command line exited with termination signal {0} ({1})
+    ProcessTerminated(i32, &'static str),
+    /// a command line exited with non-termination signal {0} ({1})
+    ProcessKilled(i32, &'static str),
+    /// a command line exited with {0}, which its exit policy rejected
+    ExitRejected(process::ExitStatus),
+    /// i/o error invoking command line: {0}
+    Io(#[from] io::Error),
+    /// utf-8 decoding error for command line: {0}
+    Utf8(#[from] str::Utf8Error),
+    /// invalid path for command line: {0}
+    Fs(#[from] fs::FsError),
+    /// executable {exe} was not found; searched {searched:?}
+    ExeNotFound {
+      /// The executable name which was requested.
+      exe: Exe,
+      /// Every path which was checked for an executable file.
+      searched: Vec<PathBuf>,
+    },
+    /// a command line was torn down after running for {after:?}
+    TimedOut {
+      /// The timeout which elapsed.
+      after: Duration,
+      /// Any output read from the process before it was torn down. This is empty for
+      /// [`crate::stream`] invocations, which pass all of their output to a callback.
+      output: RawOutput,
+    },
+    /// a command line was torn down after it was cancelled
+    Cancelled {
+      /// Any output read from the process before it was torn down. This is empty for
+      /// [`crate::stream`] invocations, which pass all of their output to a callback.
+      output: RawOutput,
+    },
+    /// stage {stage} of a pipeline failed with exit statuses {statuses:?}: {error}
+    PipelineFailed {
+      /// The index of the rightmost stage which failed.
+      stage: usize,
+      /// The exit status of every stage, from left to right.
+      statuses: Vec<process::ExitStatus>,
+      /// The error from analyzing the exit status of the failed stage.
+      #[source]
+      error: Box<CommandError>,
+    },
+  }
+
+  macro_rules! signal_pairs {
+  ($($name:ident),+) => {
+    [$(($name, stringify!($name))),+]
+  }
+  }
+
+  lazy_static! {
+    static ref SIGNAL_NAMES: IndexMap<i32, &'static str> = signal_pairs![
+      SIGABRT, SIGALRM, SIGBUS, SIGCHLD, SIGCONT, SIGFPE, SIGHUP, SIGILL, SIGINT, SIGIO, SIGKILL,
+      SIGPIPE, SIGPROF, SIGQUIT, SIGSEGV, SIGSTOP, SIGSYS, SIGTERM, SIGTRAP, SIGTSTP, SIGTTIN,
+      SIGTTOU, SIGURG, SIGUSR1, SIGUSR2, SIGVTALRM, SIGWINCH, SIGXCPU, SIGXFSZ
+    ]
+    .into();
+  }
+
+  impl CommandError {
+    /// Raise an error if the process exited with any type of failure.
+    pub fn analyze_exit_status(status: process::ExitStatus) -> Result<(), Self> {
+      if let Some(code) = status.code() {
+        if code == 0 {
+          Ok(())
+        } else {
+          Err(Self::NonZeroExit(code))
+        }
+      } else if let Some(signal) = status.signal() {
+        Err(if TERM_SIGNALS.contains(&signal) {
+          Self::ProcessTerminated(signal, SIGNAL_NAMES.get(&signal).unwrap())
+        } else {
+          Self::ProcessKilled(signal, SIGNAL_NAMES.get(&signal).unwrap())
+        })
+      } else {
+        unreachable!("status {:?} had no exit code or signal", status)
+      }
+    }
+
+    pub(crate) fn command_with_context(
+      self,
+      command: Command,
+      context: String,
+    ) -> CommandErrorWrapper {
+      CommandErrorWrapper {
+        command,
+        context,
+        error: self,
+        report: None,
+      }
+    }
+  }
+
+  /// The end of an output stream, bounded to [`Self::MAX_LENGTH`] bytes.
+  #[derive(Debug, Clone, Default, PartialEq, Eq)]
+  pub struct OutputTail {
+    /// The last bytes of the stream.
+    pub bytes: Vec<u8>,
+    /// How many bytes from the start of the stream were dropped.
+    pub skipped: usize,
+  }
+
+  impl OutputTail {
+    /// The most bytes to retain from a single stream.
+    pub const MAX_LENGTH: usize = 64 * 1024;
+
+    /// Retain the end of `output`.
+    pub fn of(output: &[u8]) -> Self {
+      let skipped = output.len().saturating_sub(Self::MAX_LENGTH);
+      Self {
+        bytes: output[skipped..].to_vec(),
+        skipped,
+      }
+    }
+  }
+
+  /// Accumulates an [`OutputTail`] from a stream which is read in chunks.
+  #[derive(Debug, Clone, Default)]
+  pub(crate) struct TailBuffer {
+    bytes: VecDeque<u8>,
+    skipped: usize,
+  }
+
+  impl TailBuffer {
+    /// Append `chunk`, dropping bytes from the front beyond [`OutputTail::MAX_LENGTH`].
+    pub(crate) fn push(&mut self, chunk: &[u8]) {
+      self.bytes.extend(chunk);
+      let excess = self.bytes.len().saturating_sub(OutputTail::MAX_LENGTH);
+      self.bytes.drain(..excess);
+      self.skipped += excess;
+    }
+
+    pub(crate) fn tail(&self) -> OutputTail {
+      OutputTail {
+        bytes: self.bytes.iter().copied().collect(),
+        skipped: self.skipped,
+      }
+    }
+  }
+
+  /// The exit status and output of a process which failed, for callers to inspect.
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::path::PathBuf;
+  /// use super_process::{fs, exe, sync::SyncInvocable};
+  ///
+  /// let command = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+  ///   argv: ["-c", "head -c 100000 /dev/zero; echo 'error: bad input' >&2; exit 2"]
+  ///     .as_ref()
+  ///     .into(),
+  ///   ..Default::default()
+  /// };
+  /// let report = command.invoke().await.unwrap_err().report.unwrap();
+  /// assert_eq!(Some(2), report.status.code());
+  /// assert_eq!(b"error: bad input\n".as_ref(), &report.stderr.bytes);
+  /// assert_eq!(100000 - exe::OutputTail::MAX_LENGTH, report.stdout.skipped);
+  /// assert!(report.elapsed.is_some());
+  /// # }) // async
+  ///```
+  ///
+  /// Streamed output is retained as it is read, and each stage of a pipeline is timed until it
+  /// exits:
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::{path::PathBuf, time::Duration};
+  /// use futures_lite::prelude::*;
+  /// use super_process::{fs, exe, pipe, stream::Streamable};
+  ///
+  /// let sh = |script: &str| exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+  ///   argv: ["-c", script].as_ref().into(),
+  ///   ..Default::default()
+  /// };
+  /// let pipeline = pipe::Pipeline::from(sh("echo early; echo 'error: bad input' >&2; exit 2"))
+  ///   .pipe(sh("cat; sleep 1"));
+  /// let mut events = Box::pin(pipeline.invoke_streaming().unwrap().into_lines());
+  /// let report = loop {
+  ///   if let Err(e) = events.next().await.unwrap() {
+  ///     break e.report.unwrap();
+  ///   }
+  /// };
+  /// assert_eq!(Some(2), report.status.code());
+  /// assert_eq!(b"early\n".as_ref(), &report.stdout.bytes);
+  /// assert_eq!(b"error: bad input\n".as_ref(), &report.stderr.bytes);
+  /// assert!(report.elapsed.unwrap() < Duration::from_millis(900));
+  /// # }) // async
+  ///```
+  #[derive(Debug, Clone)]
+  pub struct ExitReport {
+    /// How the process exited.
+    pub status: process::ExitStatus,
+    /// The end of the process's stdout. For a [`Pipeline`](crate::pipe::Pipeline), this is the
+    /// stdout of the last stage.
+    pub stdout: OutputTail,
+    /// The end of the process's stderr. For a [`Pipeline`](crate::pipe::Pipeline), this is the
+    /// stderr of every stage.
+    pub stderr: OutputTail,
+    /// How long the process ran for, if it was spawned by this crate.
+    pub elapsed: Option<Duration>,
+  }
+
+  impl CommandErrorWrapper {
+    pub(crate) fn with_report(mut self, report: ExitReport) -> Self {
+      self.report = Some(report);
+      self
+    }
+
+    /// Render this error for humans, as opposed to its [`Display`] impl which is meant for logs.
+    pub fn diagnostic(&self) -> Diagnostic<'_> {
+      Diagnostic {
+        error: self,
+        color: false,
+        stderr_lines: Diagnostic::DEFAULT_STDERR_LINES,
+      }
+    }
+  }
+
+  /// Renders a [`CommandErrorWrapper`] over multiple lines, with the command as a shell line,
+  /// the environment variables it changed, the end of its stderr, and why it failed.
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::path::PathBuf;
+  /// use super_process::{fs, exe, sync::SyncInvocable};
+  ///
+  /// let command = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+  ///   wd: Some(fs::Directory(PathBuf::from("/"))),
+  ///   argv: ["-c", "echo 'error: bad input' >&2; exit 2"].as_ref().into(),
+  ///   env: [("SUPER_PROCESS_DIAGNOSTIC", "it's set"), ("SUPER PROCESS", "x")].into(),
+  ///   ..Default::default()
+  /// };
+  /// let error = command.invoke().await.unwrap_err();
+  /// let expected = [
+  ///   "error: command failed (when analyzing exit status)",
+  ///   "  command: sh -c 'echo '\\''error: bad input'\\'' >&2; exit 2'",
+  ///   "  working directory: /",
+  ///   "  environment:",
+  ///   "    SUPER_PROCESS_DIAGNOSTIC='it'\\''s set'",
+  ///   "    'SUPER PROCESS'=x",
+  ///   "  stderr:",
+  ///   "    | error: bad input",
+  ///   "  reason: exited with status 2",
+  ///   "",
+  /// ];
+  /// assert_eq!(expected.join("\n"), error.diagnostic().to_string());
+  ///
+  /// // The single-line rendering for logs also shows the command as a shell line.
+  /// assert!(error.to_string().starts_with("command `sh -c 'echo '\\''error: bad input"));
+  /// # }) // async
+  ///```
+  #[derive(Debug, Clone)]
+  pub struct Diagnostic<'a> {
+    error: &'a CommandErrorWrapper,
+    color: bool,
+    stderr_lines: usize,
+  }
+
+  impl<'a> Diagnostic<'a> {
+    /// The default for [`Self::stderr_lines`].
+    pub const DEFAULT_STDERR_LINES: usize = 20;
+
+    /// Whether to use ANSI escape codes for emphasis.
+    pub fn color(mut self, color: bool) -> Self {
+      self.color = color;
+      self
+    }
+
+    /// Use color if our own stderr is a terminal.
+    pub fn color_if_terminal(self) -> Self { self.color(io::stderr().is_terminal()) }
+
+    /// How many lines from the end of stderr to show.
+    pub fn stderr_lines(mut self, stderr_lines: usize) -> Self {
+      self.stderr_lines = stderr_lines;
+      self
+    }
+
+    fn style(&self, code: &str, text: &str) -> String {
+      if self.color {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+      } else {
+        text.to_string()
+      }
+    }
+
+    fn label(&self, text: &str) -> String { self.style("1", text) }
+
+    /// The environment variables which differ from our own after applying [`Command::env`].
+    fn changed_env(&self) -> Vec<String> {
+      let env = &self.error.command.env;
+      if env.is_empty() {
+        return Vec::new();
+      }
+      let before: IndexMap<OsString, OsString> = env::vars_os().collect();
+      let after = env.resolve(env::vars_os());
+      let diff = EnvDiff::between(&before, &after);
+      let quote = |s: &OsStr| crate::quote::Flavor::Bash.quote(s).to_string_lossy().into_owned();
+      let set = diff
+        .added
+        .iter()
+        .chain(diff.changed.iter())
+        .map(|(k, v)| format!("{}={}", quote(k), quote(v)));
+      let unset = diff.unset.iter().map(|k| format!("unset {}", quote(k)));
+      set.chain(unset).collect()
+    }
+
+    fn reason(&self) -> String {
+      let status = match (&self.error.error, &self.error.report) {
+        (
+          CommandError::NonZeroExit(_)
+          | CommandError::ProcessTerminated(..)
+          | CommandError::ProcessKilled(..)
+          | CommandError::ExitRejected(_)
+          | CommandError::PipelineFailed { .. },
+          Some(ExitReport { status, .. }),
+        ) => *status,
+        _ => return self.error.error.to_string(),
+      };
+      if let Some(code) = status.code() {
+        format!("exited with status {}", code)
+      } else if let Some(signal) = status.signal() {
+        let name = SIGNAL_NAMES.get(&signal).copied().unwrap_or("unknown");
+        format!("killed by signal {} ({})", signal, name)
+      } else {
+        format!("{:?}", status)
+      }
+    }
+  }
+
+  impl<'a> fmt::Display for Diagnostic<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      let CommandErrorWrapper {
+        command,
+        context,
+        report,
+        ..
+      } = self.error;
+      writeln!(
+        f,
+        "{} command failed ({})",
+        self.style("1;31", "error:"),
+        context
+      )?;
+      writeln!(
+        f,
+        "  {} {}",
+        self.label("command:"),
+        crate::quote::display(command)
+      )?;
+      if let Some(ref wd) = command.wd {
+        writeln!(
+          f,
+          "  {} {}",
+          self.label("working directory:"),
+          crate::quote::Flavor::Bash.quote(wd.0.as_os_str()).to_string_lossy()
+        )?;
+      }
+      let env = self.changed_env();
+      if !env.is_empty() {
+        writeln!(f, "  {}", self.label("environment:"))?;
+        for line in env {
+          writeln!(f, "    {}", line)?;
+        }
+      }
+      if let Some(ExitReport { ref stderr, .. }) = report {
+        let text = String::from_utf8_lossy(&stderr.bytes);
+        let lines: Vec<&str> = text.lines().collect();
+        let shown = &lines[lines.len().saturating_sub(self.stderr_lines)..];
+        if !shown.is_empty() {
+          writeln!(f, "  {}", self.label("stderr:"))?;
+          if shown.len() < lines.len() || stderr.skipped > 0 {
+            writeln!(f, "    {}", self.style("2", "| ..."))?;
+          }
+          for line in shown {
+            writeln!(f, "    {} {}", self.style("2", "|"), line)?;
+          }
+        }
+      }
+      writeln!(f, "  {} {}", self.label("reason:"), self.reason())
+    }
+  }
+
+  /// An error from a [`Command`], along with where it occurred.
+  ///
+  /// This is displayed on a single line for logs, with the command rendered as a shell command
+  /// line by [`crate::quote`]. See [`Self::diagnostic`] for a rendering meant for humans.
+  #[derive(Debug, Error)]
+  pub struct CommandErrorWrapper {
+    /// The command that attempted to be executed.
+    pub command: Command,
+    /// Additional information about where the error occurred.
+    pub context: String,
+    /// The underlying error.
+    #[source]
+    pub error: CommandError,
+    /// The exit status and output of the process, if it exited unsuccessfully.
+    pub report: Option<ExitReport>,
+  }
+
+  impl fmt::Display for CommandErrorWrapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      write!(
+        f,
+        "command `{}` failed ({}): {}",
+        crate::quote::display(&self.command),
+        self.context,
+        self.error
+      )
+    }
+  }
+}
+
+/// Instrumentation for every child process spawned by this crate.
+///
+/// Each child is covered by a [`tracing`] span named `process`, which records the command line
+/// and pid when it is spawned, then the exit status, elapsed time, and output byte counts when it
+/// exits. A `debug` event is emitted at spawn and at exit, and an `info` event when an invocation
+/// fails. The span is entered while waiting on the child or reading its output, so events emitted
+/// meanwhile are nested within it. For a [`Pipeline`](crate::pipe::Pipeline), output is read
+/// within the span of the last stage.
+///
+/// [`hook::CommandHook`]s can also be [`hook::register`]ed to receive the same notifications, e.g.
+/// to feed a build log.
+///```
+/// # tokio_test::block_on(async {
+/// use std::{path::PathBuf, sync::{Arc, Mutex}};
+/// use super_process::{fs, exe, hook, quote, sync::SyncInvocable};
+///
+/// #[derive(Default)]
+/// struct Log(Mutex<Vec<String>>);
+///
+/// impl hook::CommandHook for Log {
+///   fn on_spawn(&self, event: &hook::SpawnEvent<'_>) {
+///     let line = quote::command_line(event.command);
+///     self.0.lock().unwrap().push(format!("spawn {}", line.display()));
+///   }
+///
+///   fn on_exit(&self, event: &hook::ExitEvent<'_>) {
+///     let stdout = event.output.map(|output| output.stdout);
+///     self.0.lock().unwrap().push(format!("exit {:?} {:?}", event.status.code(), stdout));
+///   }
+///
+///   fn on_error(&self, error: &exe::CommandErrorWrapper) {
+///     self.0.lock().unwrap().push(format!("error {}", error.context));
+///   }
+/// }
+///
+/// let log = Arc::new(Log::default());
+/// let id = hook::register(log.clone());
+///
+/// exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("echo"))),
+///   argv: ["hey"].as_ref().into(),
+///   ..Default::default()
+/// }
+/// .invoke()
+/// .await
+/// .unwrap();
+/// assert!(exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("false"))),
+///   ..Default::default()
+/// }
+/// .invoke()
+/// .await
+/// .is_err());
+///
+/// assert!(hook::unregister(id));
+/// assert_eq!(*log.0.lock().unwrap(), [
+///   "spawn echo hey",
+///   "exit Some(0) Some(4)",
+///   "spawn false",
+///   "exit Some(1) Some(0)",
+///   "error when analyzing exit status",
+/// ]);
+/// # }) // async
+///```
+pub mod hook {
+  use super::{exe, quote};
+
+  use lazy_static::lazy_static;
+
+  use std::{
+    process::ExitStatus,
+    sync::{
+      atomic::{AtomicU64, Ordering},
+      Arc, PoisonError, RwLock,
+    },
+    time::{Duration, SystemTime},
+  };
+
+  /// Notification that a child process was spawned.
+  #[derive(Debug, Clone, Copy)]
+  pub struct SpawnEvent<'a> {
+    /// The command which was spawned.
+    pub command: &'a exe::Command,
+    /// The pid of the child process.
+    pub pid: u32,
+    /// When the child was spawned.
+    pub started: SystemTime,
+  }
+
+  /// The number of bytes read from a child's output streams.
+  #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+  #[allow(missing_docs)]
+  pub struct OutputBytes {
+    pub stdout: usize,
+    pub stderr: usize,
+  }
+
+  /// Notification that a child process exited.
+  #[derive(Debug, Clone, Copy)]
+  pub struct ExitEvent<'a> {
+    /// The command which exited.
+    pub command: &'a exe::Command,
+    /// The pid of the child process.
+    pub pid: u32,
+    /// The exit status of the child process.
+    pub status: ExitStatus,
+    /// The time between spawning the child and observing its exit.
+    pub elapsed: Duration,
+    /// How much output was read from the child, if this crate collected it all, e.g. with
+    /// [`crate::sync::SyncInvocable`]. This is not known for the upstream stages of a
+    /// [`crate::pipe::Pipeline`], or when output is streamed.
+    pub output: Option<OutputBytes>,
+  }
+
+  /// Receives notifications about every child process spawned by this crate.
+  ///
+  /// Hooks are called synchronously from whichever task spawns or waits on the child, so they
+  /// should return quickly.
+  pub trait CommandHook: Send+Sync {
+    /// Called after a child process is spawned.
+    fn on_spawn(&self, _event: &SpawnEvent<'_>) {}
+
+    /// Called the first time a child process is observed to have exited.
+    fn on_exit(&self, _event: &ExitEvent<'_>) {}
+
+    /// Called when an invocation fails, including when a process exits unsuccessfully.
+    fn on_error(&self, _error: &exe::CommandErrorWrapper) {}
+  }
+
+  /// A handle to a hook added with [`register`], which can be passed to [`unregister`].
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+  pub struct HookId(u64);
+
+  lazy_static! {
+    static ref HOOKS: RwLock<Vec<(HookId, Arc<dyn CommandHook>)>> = RwLock::new(Vec::new());
+  }
+
+  static NEXT_HOOK_ID: AtomicU64 = AtomicU64::new(0);
+
+  /// Add `hook` to the global registry, so it is notified about every subsequent child process.
+  pub fn register(hook: Arc<dyn CommandHook>) -> HookId {
+    let id = HookId(NEXT_HOOK_ID.fetch_add(1, Ordering::Relaxed));
+    HOOKS
+      .write()
+      .unwrap_or_else(PoisonError::into_inner)
+      .push((id, hook));
+    id
+  }
+
+  /// Remove the hook registered as `id`, returning whether it was found.
+  pub fn unregister(id: HookId) -> bool {
+    let mut hooks = HOOKS.write().unwrap_or_else(PoisonError::into_inner);
+    let len = hooks.len();
+    hooks.retain(|(hook_id, _)| *hook_id != id);
+    hooks.len() != len
+  }
+
+  /// Call `f` on every registered hook.
+  fn notify(f: impl Fn(&dyn CommandHook)) {
+    /* Release the lock before calling out, so that hooks may register or unregister hooks. */
+    let hooks: Vec<Arc<dyn CommandHook>> = HOOKS
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .iter()
+      .map(|(_, hook)| hook.clone())
+      .collect();
+    for hook in hooks.iter() {
+      f(hook.as_ref());
+    }
+  }
+
+  /// Report that `command` was spawned as `pid`, returning the span which covers its lifetime.
+  pub(crate) fn spawned(command: &exe::Command, pid: u32) -> tracing::Span {
+    let span = tracing::info_span!(
+      "process",
+      command = %quote::display(command),
+      pid,
+      status = tracing::field::Empty,
+      elapsed_ms = tracing::field::Empty,
+      stdout_bytes = tracing::field::Empty,
+      stderr_bytes = tracing::field::Empty,
+    );
+    tracing::debug!(parent: &span, "spawned");
+    let event = SpawnEvent {
+      command,
+      pid,
+      started: SystemTime::now(),
+    };
+    notify(|hook| hook.on_spawn(&event));
+    span
+  }
+
+  pub(crate) fn exited(span: &tracing::Span, event: &ExitEvent<'_>) {
+    span.record("status", tracing::field::display(event.status));
+    span.record("elapsed_ms", event.elapsed.as_millis() as u64);
+    if let Some(OutputBytes { stdout, stderr }) = event.output {
+      span.record("stdout_bytes", stdout);
+      span.record("stderr_bytes", stderr);
+    }
+    tracing::debug!(parent: span, status = %event.status, "exited");
+    notify(|hook| hook.on_exit(event));
+  }
+
+  /// Report `error` from a failed invocation, then return it.
+  pub(crate) fn failed(error: exe::CommandErrorWrapper) -> exe::CommandErrorWrapper {
+    tracing::info!(
+      command = %quote::display(&error.command),
+      context = %error.context,
+      error = %error.error,
+      "command failed",
+    );
+    notify(|hook| hook.on_error(&error));
+    error
+  }
+}
+
+/// Render command lines which can be pasted into a POSIX shell, and parse them back.
+///```
+/// use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+/// use super_process::quote;
+///
+/// assert_eq!(OsStr::new("plain/arg-1.txt"), quote::quote(OsStr::new("plain/arg-1.txt")));
+/// assert_eq!(OsStr::new("'two words'"), quote::quote(OsStr::new("two words")));
+/// assert_eq!(OsStr::new("''"), quote::quote(OsStr::new("")));
+/// assert_eq!(OsStr::new("'it'\\''s'"), quote::quote(OsStr::new("it's")));
+/// // Bytes which are not valid UTF-8 are kept as they are.
+/// assert_eq!(OsStr::from_bytes(b"'a\xff'\\'''"), quote::quote(OsStr::from_bytes(b"a\xff'")));
+/// // They can be escaped instead for shells which support `$'...'` strings.
+/// let bash = quote::Flavor::Bash;
+/// assert_eq!(OsStr::new("$'a\\xff\\''"), bash.quote(OsStr::from_bytes(b"a\xff'")));
+///```
+///
+/// [`command_line`](crate::quote::command_line) and [`parse`](crate::quote::parse) are inverses
+/// of each other in either [`Flavor`](crate::quote::Flavor), even for arguments which are not
+/// valid UTF-8:
+///
+///```
+/// # fn main() -> Result<(), super_process::quote::ParseError> {
+/// use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+/// use super_process::{exe, quote};
+///
+/// let argv = [
+///   OsStr::new("it's"),
+///   OsStr::new("two words"),
+///   OsStr::new(""),
+///   OsStr::new("$HOME \\ \"x\""),
+///   OsStr::new("line\nbreak"),
+///   OsStr::from_bytes(b"caf\xe9 '\\'"),
+/// ];
+/// let command = exe::Command {
+///   exe: exe::Exe::from(&"printf"),
+///   argv: argv.into(),
+///   ..Default::default()
+/// };
+/// let line = quote::Flavor::Bash.command_line(&command);
+/// assert_eq!(
+///   line,
+///   [
+///     "printf 'it'\\''s' 'two words' '' '$HOME \\ \"x\"' 'line",
+///     "break' $'caf\\xe9 \\'\\\\\\''",
+///   ]
+///   .join("\n")
+///   .as_str()
+/// );
+///
+/// for line in [line, quote::command_line(&command)] {
+///   let (exe, parsed) = quote::parse(&line)?;
+///   assert_eq!(exe, command.exe);
+///   assert_eq!(parsed, command.argv);
+/// }
+/// # Ok(())
+/// # }
+///```
+pub mod quote {
+  use super::{exe, fs};
+
+  use displaydoc::Display;
+  use indexmap::IndexMap;
+  use thiserror::Error;
+
+  use std::{
+    borrow::Cow,
+    env,
+    ffi::{OsStr, OsString},
+    iter,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    str,
+  };
+
+  /// Whether `b` never needs to be quoted.
+  fn is_safe(b: u8) -> bool { b.is_ascii_alphanumeric() || b"@%+=:,./-_".contains(&b) }
+
+  /// Join `words` with spaces.
+  fn join<S: AsRef<OsStr>>(words: impl IntoIterator<Item=S>) -> OsString {
+    let mut line = OsString::new();
+    for (index, word) in words.into_iter().enumerate() {
+      if index > 0 {
+        line.push(" ");
+      }
+      line.push(word);
+    }
+    line
+  }
+
+  /// The shell syntax to render command lines with.
+  #[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+  pub enum Flavor {
+    /// Any POSIX shell. Arguments are wrapped in single quotes, and bytes which are not valid
+    /// UTF-8 are kept as they are, so the output may not be valid UTF-8 either.
+    #[default]
+    Posix,
+    /// `bash`, `zsh`, and other shells which support `$'...'` strings. Arguments which are not
+    /// valid UTF-8 are rendered as `$'...'` strings with `\xNN` escapes, so the output is always
+    /// valid UTF-8.
+    Bash,
+  }
+
+  impl Flavor {
+    /// Quote `arg` so that this shell reads it as a single word.
+    pub fn quote(self, arg: &OsStr) -> Cow<'_, OsStr> {
+      let bytes = arg.as_bytes();
+      if !bytes.is_empty() && bytes.iter().copied().all(is_safe) {
+        return Cow::Borrowed(arg);
+      }
+      let mut quoted: Vec<u8> = Vec::with_capacity(bytes.len() + 2);
+      if self == Self::Bash && str::from_utf8(bytes).is_err() {
+        quoted.extend_from_slice(b"$'");
+        for b in bytes.iter().copied() {
+          match b {
+            b'\\' | b'\'' => quoted.extend_from_slice(&[b'\\', b]),
+            b' '..=b'~' => quoted.push(b),
+            _ => quoted.extend_from_slice(format!("\\x{:02x}", b).as_bytes()),
+          }
+        }
+      } else {
+        quoted.push(b'\'');
+        for b in bytes.iter().copied() {
+          match b {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            _ => quoted.push(b),
+          }
+        }
+      }
+      quoted.push(b'\'');
+      Cow::Owned(OsString::from_vec(quoted))
+    }
+
+    /// Render the executable and arguments of `command` as a shell command line.
+    pub fn command_line(self, command: &exe::Command) -> OsString {
+      let exe::Command { exe, argv, .. } = command;
+      let exe::Exe(fs::File(exe)) = exe;
+      join(
+        iter::once(exe.as_os_str())
+          .chain(argv.0.iter().map(|arg| arg.as_os_str()))
+          .map(|arg| self.quote(arg)),
+      )
+    }
+
+    /// Render `command` as a line which can be pasted into a shell to reproduce it. See
+    /// [`invocation`].
+    pub fn invocation(self, command: &exe::Command) -> OsString {
+      let word = |word: &'static str| Cow::Borrowed(OsStr::new(word));
+      let (unset, env_args) = self.env_prefix(&command.env);
+      let mut words: Vec<Cow<'_, OsStr>> = Vec::new();
+      if let Some(fs::Directory(wd)) = &command.wd {
+        words.extend([word("cd"), self.quote(wd.as_os_str()), word("&&")]);
+      }
+      if !env_args.is_empty() {
+        words.push(word("env"));
+        words.extend(env_args);
+      }
+      words.push(Cow::Owned(self.command_line(command)));
+      let line = join(words);
+      if unset.is_empty() {
+        return line;
+      }
+      /* `env -u` is not specified by POSIX, so unset variables in a subshell instead. */
+      let mut subshell = OsString::from("(unset ");
+      subshell.push(join(unset));
+      subshell.push("; ");
+      subshell.push(line);
+      subshell.push(")");
+      subshell
+    }
+
+    /// The variables to unset, and the arguments to `env` which apply the rest of the
+    /// modifications in `env`.
+    fn env_prefix(self, env: &exe::EnvModifications) -> (Vec<OsString>, Vec<Cow<'_, OsStr>>) {
+      if env.is_empty() {
+        return (Vec::new(), Vec::new());
+      }
+      let assignment = |(k, v): (&OsString, &OsString)| {
+        let mut binding = k.clone();
+        binding.push("=");
+        binding.push(v);
+        Cow::Owned(self.quote(&binding).into_owned())
+      };
+      let before: IndexMap<OsString, OsString> = env::vars_os().collect();
+      let after = env.resolve(env::vars_os());
+      let clears = env
+        .0
+        .iter()
+        .any(|op| matches!(op, exe::EnvOp::Clear | exe::EnvOp::Retain(_)));
+      if clears {
+        let env_args = iter::once(Cow::Borrowed(OsStr::new("-i")))
+          .chain(after.iter().map(assignment))
+          .collect();
+        return (Vec::new(), env_args);
+      }
+      let diff = exe::EnvDiff::between(&before, &after);
+      let unset = diff
+        .unset
+        .iter()
+        .map(|k| self.quote(k).into_owned())
+        .collect();
+      let set = diff
+        .added
+        .iter()
+        .chain(diff.changed.iter())
+        .map(assignment)
+        .collect();
+      (unset, set)
+    }
+  }
+
+  /// Quote `arg` so that a POSIX shell reads it as a single word. See [`Flavor::quote`].
+  pub fn quote(arg: &OsStr) -> Cow<'_, OsStr> { Flavor::Posix.quote(arg) }
+
+  /// Render the executable and arguments of `command` as a POSIX shell command line.
+  pub fn command_line(command: &exe::Command) -> OsString { Flavor::Posix.command_line(command) }
+
+  /// Render `command` for logs and error messages, which must be valid UTF-8.
+  pub(crate) fn display(command: &exe::Command) -> String {
+    Flavor::Bash
+      .command_line(command)
+      .to_string_lossy()
+      .into_owned()
+  }
+
+  /// Render `command` as a line which can be pasted into a POSIX shell to reproduce it.
+  ///
+  /// In addition to [`command_line`], this changes into the working directory with `cd` and
+  /// applies environment modifications with an `env` prefix. Variables are unset with `unset` in
+  /// a subshell, since `env -u` is not specified by POSIX. Modifications are resolved against the
+  /// current process's environment, so e.g. a [`Prepend`](exe::EnvOp::Prepend) to `$PATH` is
+  /// rendered with its full value.
+  ///
+  ///```
+  /// use super_process::{exe, fs, quote};
+  ///
+  /// let command = exe::Command {
+  ///   exe: exe::Exe::from(&"make"),
+  ///   wd: Some(fs::Directory("/tmp/my build".into())),
+  ///   argv: ["-j4"].into(),
+  ///   env: exe::EnvModifications(vec![
+  ///     exe::EnvOp::Unset("SUPER_PROCESS_QUOTE_UNSET".into()),
+  ///     exe::EnvOp::Set("SUPER_PROCESS_QUOTE_SET".into(), "it's set".into()),
+  ///   ]),
+  ///   ..Default::default()
+  /// };
+  /// std::env::set_var("SUPER_PROCESS_QUOTE_UNSET", "1");
+  /// assert_eq!(
+  ///   quote::invocation(&command),
+  ///   "(unset SUPER_PROCESS_QUOTE_UNSET; cd '/tmp/my build' && \
+  ///    env 'SUPER_PROCESS_QUOTE_SET=it'\\''s set' make -j4)",
+  /// );
+  ///
+  /// // An empty environment is rendered with `env -i`.
+  /// let command = exe::Command {
+  ///   exe: exe::Exe::from(&"true"),
+  ///   env: exe::EnvModifications(vec![exe::EnvOp::Clear]),
+  ///   ..Default::default()
+  /// };
+  /// assert_eq!(quote::invocation(&command), "env -i true");
+  ///```
+  ///
+  /// Running the line with `sh -c` reproduces the working directory and environment:
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::ffi::OsString;
+  /// use super_process::{exe, fs, quote, sync::SyncInvocable};
+  ///
+  /// let dir = tempfile::tempdir().unwrap();
+  /// let wd = dir.path().join("it's here");
+  /// std::fs::create_dir(&wd).unwrap();
+  /// std::env::set_var("SUPER_PROCESS_REPLAY_UNSET", "1");
+  /// let command = exe::Command {
+  ///   exe: exe::Exe::from(&"sh"),
+  ///   wd: Some(fs::Directory(wd.clone())),
+  ///   argv: [
+  ///     "-c",
+  ///     r#"pwd; echo "$SUPER_PROCESS_REPLAY_SET"; echo "${SUPER_PROCESS_REPLAY_UNSET-unset}""#,
+  ///   ]
+  ///   .as_ref()
+  ///   .into(),
+  ///   env: exe::EnvModifications(vec![
+  ///     exe::EnvOp::Unset("SUPER_PROCESS_REPLAY_UNSET".into()),
+  ///     exe::EnvOp::Set("SUPER_PROCESS_REPLAY_SET".into(), "a 'b' $c".into()),
+  ///   ]),
+  ///   ..Default::default()
+  /// };
+  /// let replay = exe::Command {
+  ///   exe: exe::Exe::from(&"sh"),
+  ///   argv: exe::Argv([OsString::from("-c"), quote::invocation(&command)].into()),
+  ///   ..Default::default()
+  /// };
+  ///
+  /// let expected = format!("{}\na 'b' $c\nunset\n", wd.display());
+  /// assert_eq!(expected.as_bytes(), &command.invoke().await.unwrap().stdout);
+  /// assert_eq!(expected.as_bytes(), &replay.invoke().await.unwrap().stdout);
+  /// # }) // async
+  ///```
+  pub fn invocation(command: &exe::Command) -> OsString { Flavor::Posix.invocation(command) }
+
+  /// Errors that may occur when parsing a shell command line.
+  #[derive(Debug, Display, Error, Clone, PartialEq, Eq)]
+  pub enum ParseError {
+    /// unterminated {0} quote starting at byte {1}
+    UnterminatedQuote(&'static str, usize),
+    /// backslash at end of input
+    TrailingBackslash,
+    /// invalid escape sequence at byte {0}
+    InvalidEscape(usize),
+    /// command line contained no words
+    Empty,
+  }
+
+  /// Split `line` into words as a POSIX shell would, without performing any expansions.
+  ///
+  /// This understands single quotes, double quotes, backslash escapes, `$'...'` strings as
+  /// produced by [`Flavor::Bash`], line continuations, and `#` comments. Other shell syntax such
+  /// as `$VAR` or `|` is kept literally.
+  ///
+  ///```
+  /// use std::ffi::OsStr;
+  /// use super_process::quote;
+  ///
+  /// let words = quote::split(OsStr::new(r#"a\ b "c \"d\"" 'e'f $'\x41\n' # comment"#)).unwrap();
+  /// assert_eq!(words, ["a b", "c \"d\"", "ef", "A\n"]);
+  ///
+  /// assert_eq!(
+  ///   quote::split(OsStr::new("echo 'oops")),
+  ///   Err(quote::ParseError::UnterminatedQuote("single", 5)),
+  /// );
+  ///```
+  pub fn split(line: &OsStr) -> Result<Vec<OsString>, ParseError> {
+    let bytes = line.as_bytes();
+    let mut words: Vec<OsString> = Vec::new();
+    let mut word: Option<Vec<u8>> = None;
+    let mut i = 0;
+    while let Some(&b) = bytes.get(i) {
+      match b {
+        b' ' | b'\t' | b'\n' => {
+          words.extend(word.take().map(OsString::from_vec));
+          i += 1;
+        },
+        b'#' if word.is_none() => {
+          while bytes.get(i).is_some_and(|&b| b != b'\n') {
+            i += 1;
+          }
+        },
+        b'\\' => match bytes.get(i + 1) {
+          None => return Err(ParseError::TrailingBackslash),
+          Some(b'\n') => i += 2,
+          Some(&c) => {
+            word.get_or_insert_with(Vec::new).push(c);
+            i += 2;
+          },
+        },
+        b'\'' => {
+          let len = bytes[i + 1..]
+            .iter()
+            .position(|&c| c == b'\'')
+            .ok_or(ParseError::UnterminatedQuote("single", i))?;
+          word
+            .get_or_insert_with(Vec::new)
+            .extend_from_slice(&bytes[i + 1..i + 1 + len]);
+          i += len + 2;
+        },
+        b'"' => i = parse_double_quoted(bytes, i, word.get_or_insert_with(Vec::new))?,
+        b'$' if bytes.get(i + 1) == Some(&b'\'') => {
+          i = parse_ansi_c_quoted(bytes, i, word.get_or_insert_with(Vec::new))?;
+        },
+        _ => {
+          word.get_or_insert_with(Vec::new).push(b);
+          i += 1;
+        },
+      }
+    }
+    words.extend(word.map(OsString::from_vec));
+    Ok(words)
+  }
+
+  /// Parse the double-quoted string starting at `bytes[start]` into `word`, returning the index
+  /// after the closing quote.
+  fn parse_double_quoted(
+    bytes: &[u8],
+    start: usize,
+    word: &mut Vec<u8>,
+  ) -> Result<usize, ParseError> {
+    let mut i = start + 1;
+    loop {
+      match bytes.get(i) {
+        None => return Err(ParseError::UnterminatedQuote("double", start)),
+        Some(b'"') => return Ok(i + 1),
+        Some(b'\\') => match bytes.get(i + 1) {
+          Some(&c @ (b'$' | b'`' | b'"' | b'\\')) => {
+            word.push(c);
+            i += 2;
+          },
+          Some(b'\n') => i += 2,
+          _ => {
+            word.push(b'\\');
+            i += 1;
+          },
+        },
+        Some(&c) => {
+          word.push(c);
+          i += 1;
+        },
+      }
+    }
+  }
+
+  /// Parse the `$'...'` string starting at `bytes[start]` into `word`, returning the index after
+  /// the closing quote.
+  fn parse_ansi_c_quoted(
+    bytes: &[u8],
+    start: usize,
+    word: &mut Vec<u8>,
+  ) -> Result<usize, ParseError> {
+    /// Read up to `max` digits in `radix` from the start of `bytes`.
+    fn digits(bytes: &[u8], radix: u32, max: usize) -> (u8, usize) {
+      let mut value: u32 = 0;
+      let mut len = 0;
+      while let Some(d) = bytes
+        .get(len)
+        .filter(|_| len < max)
+        .and_then(|&c| (c as char).to_digit(radix))
+      {
+        value = value * radix + d;
+        len += 1;
+      }
+      (value as u8, len)
+    }
+
+    let mut i = start + 2;
+    loop {
+      match bytes.get(i) {
+        None => return Err(ParseError::UnterminatedQuote("ANSI-C", start)),
+        Some(b'\'') => return Ok(i + 1),
+        Some(b'\\') => {
+          let escaped = match bytes.get(i + 1) {
+            None => return Err(ParseError::UnterminatedQuote("ANSI-C", start)),
+            Some(&c) => c,
+          };
+          i += 2;
+          let c = match escaped {
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'e' | b'E' => 0x1b,
+            b'f' => 0x0c,
+            b'v' => 0x0b,
+            b'\\' | b'\'' | b'"' | b'?' => escaped,
+            b'x' => {
+              let (value, len) = digits(&bytes[i..], 16, 2);
+              if len == 0 {
+                return Err(ParseError::InvalidEscape(i - 2));
+              }
+              i += len;
+              value
+            },
+            b'0'..=b'7' => {
+              let (value, len) = digits(&bytes[i - 1..], 8, 3);
+              i += len - 1;
+              value
+            },
+            _ => return Err(ParseError::InvalidEscape(i - 2)),
+          };
+          word.push(c);
+        },
+        Some(&c) => {
+          word.push(c);
+          i += 1;
+        },
+      }
+    }
+  }
+
+  /// Parse `line` into an executable and its arguments.
+  ///
+  /// The first word of `line` becomes the [`exe::Exe`], and the remaining words become the
+  /// [`exe::Argv`]. See [`split`] for the syntax which is understood.
+  pub fn parse(line: &OsStr) -> Result<(exe::Exe, exe::Argv), ParseError> {
+    let mut words = split(line)?.into_iter();
+    let exe = words.next().ok_or(ParseError::Empty)?;
+    Ok((exe::Exe::from(&exe), exe::Argv(words.collect())))
+  }
+}
+
+/// Extend the concept of a "process" to include setup, to enable abstraction.
+pub mod base {
+  use super::*;
+
+  use async_trait::async_trait;
+  use displaydoc::Display;
+  use thiserror::Error;
+
+  use std::io;
+
+  /// Errors which may occur during the execution of [`CommandBase::setup_command`].
+  #[derive(Debug, Display, Error)]
+  pub enum SetupError {
+    /// inner error: {0}
+    Inner(#[source] Box<SetupErrorWrapper>),
+    /// i/o error: {0}
+    Io(#[from] io::Error),
+  }
+
+  impl SetupError {
+    /// Wrap a raw error with `context` to produced a wrapped error.
+    pub fn with_context(self, context: String) -> SetupErrorWrapper {
+      SetupErrorWrapper {
+        context,
+        error: self,
+      }
+    }
+  }
+
+  /// setup error ({context}): {error}
+  #[derive(Debug, Display, Error)]
+  pub struct SetupErrorWrapper {
+    /// Additional information about where the error occurred.
+    pub context: String,
+    /// The underlying error.
+    #[source]
+    pub error: SetupError,
+  }
+
+  /// Declare higher-level operations which desugar to command lines by implementing this trait.
+  #[async_trait]
+  pub trait CommandBase {
+    /// Generate a command line from the given object.
+    async fn setup_command(self) -> Result<exe::Command, SetupError>;
+  }
+}
+
+/// Methods to execute a process "synchronously", i.e. waiting until it has exited.
+///
+///```
+/// # tokio_test::block_on(async {
+/// use std::{str, path::PathBuf};
+/// use super_process::{fs, exe, sync::SyncInvocable};
+///
+/// let command = exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("echo"))),
+///   argv: ["hey"].as_ref().into(),
+///   ..Default::default()
+/// };
+///
+/// // Spawn the child process and wait for it to end.
+/// let output = command.clone().invoke().await.expect("sync subprocess failed");
+/// // Parse stdout into utf8...
+/// let hey = str::from_utf8(&output.stdout).expect("utf8 decoding failed")
+///   // ...and strip the trailing newline.
+///   .strip_suffix("\n")
+///   .expect("trailing newline not found");
+/// assert_eq!(hey, "hey");
+/// # }) // async
+///```
+pub mod sync {
+  use super::{exe, pipe::Pipeline};
+
+  use async_process::{ChildStderr, ChildStdin, ChildStdout};
+  use async_trait::async_trait;
+  use futures_lite::{
+    future,
+    io::{self, AsyncRead, AsyncReadExt},
+  };
+
+  use std::{process, str, time::Duration};
+
+  /// The slurped streams for a synchronously-invoked process, as raw bytes.
+  #[derive(Debug, Clone, Default)]
+  #[allow(missing_docs)]
+  pub struct RawOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The exit status of the process, or of the last stage of a [`Pipeline`], if it exited.
+    pub status: Option<process::ExitStatus>,
+  }
+
+  impl RawOutput {
+    /// Parse the process's exit status with [`exe::Command::exit_policy`].
+    pub fn extract(
+      command: exe::Command,
+      output: process::Output,
+    ) -> Result<Self, exe::CommandErrorWrapper> {
+      let process::Output {
+        status,
+        stdout,
+        stderr,
+      } = output;
+
+      let output = Self {
+        stdout,
+        stderr,
+        status: Some(status),
+      };
+      command
+        .exit_policy
+        .check(status)
+        .map_err(|e| output.failed(e, command, status, None))?;
+
+      Ok(output)
+    }
+
+    /// Attach this output to the error from analyzing the exit `status` of `command`.
+    pub(crate) fn failed(
+      &self,
+      error: exe::CommandError,
+      command: exe::Command,
+      status: process::ExitStatus,
+      elapsed: Option<Duration>,
+    ) -> exe::CommandErrorWrapper {
+      error
+        .command_with_context(command, "when analyzing exit status".to_string())
+        .with_report(self.report(status, elapsed))
+    }
+
+    fn report(&self, status: process::ExitStatus, elapsed: Option<Duration>) -> exe::ExitReport {
+      exe::ExitReport {
+        status,
+        stdout: exe::OutputTail::of(&self.stdout),
+        stderr: exe::OutputTail::of(&self.stderr),
+        elapsed,
+      }
+    }
+
+    /// Decode the output streams of this process, with the invoking `command` provided for
+    /// error context.
+    ///
+    /// If decoding fails, the output itself is not rendered into the error's context, since it
+    /// may be arbitrarily long. Instead, if the exit status is known, the end of each stream is
+    /// attached as an [`exe::ExitReport`].
+    ///```
+    /// use super_process::{exe, sync::RawOutput};
+    /// use std::{os::unix::process::ExitStatusExt, process::ExitStatus};
+    ///
+    /// let output = RawOutput {
+    ///   stdout: b"\xff".repeat(100000),
+    ///   stderr: Vec::new(),
+    ///   status: Some(ExitStatus::from_raw(0)),
+    /// };
+    /// let error = output.decode(exe::Command::default()).unwrap_err();
+    /// assert_eq!("when decoding stdout", error.context);
+    /// assert!(matches!(error.error, exe::CommandError::Utf8(_)));
+    /// assert!(error.to_string().len() < 1000);
+    /// let diagnostic = error.diagnostic().to_string();
+    /// assert!(diagnostic.ends_with("reason: utf-8 decoding error for command line: invalid utf-8 \
+    ///                               sequence of 1 bytes from index 0\n"));
+    /// let report = error.report.unwrap();
+    /// assert_eq!(exe::OutputTail::MAX_LENGTH, report.stdout.bytes.len());
+    ///```
+    pub fn decode(self, command: exe::Command) -> Result<DecodedOutput, exe::CommandErrorWrapper> {
+      let decode = |bytes: &[u8], context: &str| {
+        str::from_utf8(bytes).map(str::to_string).map_err(|e| {
+          let e = exe::CommandError::from(e).command_with_context(command.clone(), context.into());
+          match self.status {
+            Some(status) => e.with_report(self.report(status, None)),
+            None => e,
+          }
+        })
+      };
+      let stdout = decode(&self.stdout, "when decoding stdout")?;
+      let stderr = decode(&self.stderr, "when decoding stderr")?;
+      Ok(DecodedOutput {
+        stdout,
+        stderr,
+        status: self.status,
+      })
+    }
+  }
+
+  /// The slurped streams for a synchronously-invoked process, after UTF-8 decoding.
+  #[derive(Debug, Clone)]
+  #[allow(missing_docs)]
+  pub struct DecodedOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// See [`RawOutput::status`].
+    pub status: Option<process::ExitStatus>,
+  }
+
+  /// Trait that defines "synchronously" invokable processes.
+  #[async_trait]
+  pub trait SyncInvocable {
+    /// Invoke a child process and wait on it to complete while slurping its output.
+    async fn invoke(self) -> Result<RawOutput, exe::CommandErrorWrapper>;
+
+    /// Invoke a child process, copying all of `stdin` into its standard input while slurping its
+    /// output.
+    ///
+    /// This overrides any [`exe::Stdin`] configured for the child.
+    async fn invoke_with_stdin<R>(self, stdin: R) -> Result<RawOutput, exe::CommandErrorWrapper>
+    where R: AsyncRead+Unpin+Send+'static;
+  }
+
+  /// Write all of `input` into the child's stdin while reading its stdout and stderr, so that
+  /// neither side can block the other on a full pipe buffer.
+  ///
+  /// Output is appended to `stdout_buf` and `stderr_buf` as it is read, so they contain any
+  /// partial output if this future is dropped before completing.
+  pub(crate) async fn communicate<R: AsyncRead+Unpin>(
+    child_stdin: Option<ChildStdin>,
+    mut stdout: ChildStdout,
+    mut stderr: ChildStderr,
+    input: Option<R>,
+    stdout_buf: &mut Vec<u8>,
+    stderr_buf: &mut Vec<u8>,
+  ) -> io::Result<()> {
+    let write_stdin = async move {
+      if let (Some(mut child_stdin), Some(input)) = (child_stdin, input) {
+        match io::copy(input, &mut child_stdin).await {
+          /* The child may exit without reading all of its input, which is not our problem. */
+          Err(e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+          result => {
+            result?;
+          },
+        }
+        /* Dropping the handle closes the pipe, which signals EOF. */
+      }
+      io::Result::Ok(())
+    };
+    let read_stdout = stdout.read_to_end(stdout_buf);
+    let read_stderr = stderr.read_to_end(stderr_buf);
+
+    future::try_zip(future::try_zip(write_stdin, read_stdout), read_stderr).await?;
+    Ok(())
+  }
+
+  #[async_trait]
+  impl SyncInvocable for exe::Command {
+    async fn invoke(self) -> Result<RawOutput, exe::CommandErrorWrapper> {
+      let input = match self.stdin {
+        Some(exe::Stdin::Bytes(ref bytes)) => Some(io::Cursor::new(bytes.clone())),
+        _ => None,
+      };
+      Pipeline::from(self).invoke_with_input(input).await
+    }
+
+    async fn invoke_with_stdin<R>(self, stdin: R) -> Result<RawOutput, exe::CommandErrorWrapper>
+    where R: AsyncRead+Unpin+Send+'static {
+      Pipeline::from(self).invoke_with_input(Some(stdin)).await
+    }
+  }
+}
+
+/// Methods to execute a process in an "asynchronous" or "streaming" fashion.
+///
+/// [`stream::Streaming::into_chunks`] and [`stream::Streaming::into_lines`] merge the stdout and
+/// stderr of a child process into a single [`Stream`](futures_lite::Stream) of
+/// [`StdioEvent`](stream::StdioEvent)s, ending with its exit status. The lines stream is built
+/// directly from a [`BufReader`](futures_lite::io::BufReader) instead of on top of the chunks
+/// stream, since re-implementing line buffering over chunks would be neither more efficient nor
+/// cleaner.
+///
+///```
+/// # tokio_test::block_on(async {
+/// use std::path::PathBuf;
+/// use futures_lite::io::AsyncReadExt;
+/// use super_process::{fs, exe, stream::Streamable};
+///
+/// let command = exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("echo"))),
+///   argv: ["hey"].as_ref().into(),
+///   ..Default::default()
+/// };
+///
+/// // Spawn the child process and stream its output, ignoring stderr for now.
+/// let mut streaming = command.invoke_streaming().expect("streaming subprocess failed");
+/// // Slurp stdout all at once into a string.
+/// let mut out: String = "".to_string();
+/// streaming.stdout.read_to_string(&mut out).await.expect("reading stdout failed");
+///
+/// // Now verify the process exited successfully.
+/// streaming.wait().await.expect("streaming command should have succeeded");
+///
+/// // Validate we get the same output streaming.
+/// let hey = out.strip_suffix("\n").unwrap();
+/// assert!(hey == "hey");
+/// # }) // async
+///```
+///
+/// The merged events can be consumed with the usual stream combinators:
+///```
+/// # tokio_test::block_on(async {
+/// use std::path::PathBuf;
+/// use futures_lite::prelude::*;
+/// use super_process::{fs, exe, stream::{Streamable, StdioEvent, StdioLine}};
+///
+/// let command = exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+///   argv: ["-c", "echo out; echo err >&2; echo out2"].as_ref().into(),
+///   ..Default::default()
+/// };
+/// let events: Vec<_> = command
+///   .invoke_streaming()
+///   .unwrap()
+///   .into_lines()
+///   .try_collect()
+///   .await
+///   .expect("streaming command should have succeeded");
+///
+/// let out: Vec<StdioLine> = events
+///   .iter()
+///   .filter_map(|event| match event {
+///     StdioEvent::Output(line @ StdioLine::Out(_)) => Some(line.clone()),
+///     _ => None,
+///   })
+///   .collect();
+/// assert_eq!(out, vec![StdioLine::Out("out".to_string()), StdioLine::Out("out2".to_string())]);
+/// assert!(events.contains(&StdioEvent::Output(StdioLine::Err("err".to_string()))));
+/// match events.last().unwrap() {
+///   StdioEvent::Exit(status) => assert!(status.success()),
+///   _ => unreachable!(),
+/// }
+/// # }) // async
+///```
+pub mod stream {
+  use super::{
+    exe, hook,
+    pipe::{self, Pipeline},
+    sync::RawOutput,
+  };
+
+  use async_io::Async;
+  use async_process::{self, ChildStderr, ChildStdin, ChildStdout, ExitStatus};
+  use futures_lite::{future, io::BufReader, prelude::*, stream};
+  use tracing::Instrument;
+
+  use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    str,
+    sync::Arc,
+    task::{Context, Poll},
+  };
+
+  /// Read chunks from `reader` until EOF or the first error.
+  pub(crate) fn read_chunks<R: AsyncRead+Unpin>(
+    reader: R,
+    wrap: fn(Vec<u8>) -> StdioChunk,
+  ) -> impl Stream<Item=io::Result<StdioChunk>> {
+    stream::unfold(Some(reader), move |reader| async move {
+      let mut reader = reader?;
+      let mut buf = vec![0u8; 300];
+      match reader.read(&mut buf).await {
+        Ok(0) => None,
+        Ok(num_read) => {
+          buf.truncate(num_read);
+          Some((Ok(wrap(buf)), Some(reader)))
+        },
+        Err(e) => Some((Err(e), None)),
+      }
+    })
+  }
+
+  /// Poll `s` unless it has already ended, recording whether it has ended in `done`.
+  fn poll_unless_done<T>(
+    s: Pin<&mut impl Stream<Item=T>>,
+    done: &mut bool,
+    cx: &mut Context<'_>,
+  ) -> Option<T> {
+    if *done {
+      return None;
+    }
+    match s.poll_next(cx) {
+      Poll::Ready(Some(item)) => Some(item),
+      Poll::Ready(None) => {
+        *done = true;
+        None
+      },
+      Poll::Pending => None,
+    }
+  }
+
+  /// Interleave the items of `a` and `b` as they become ready, ending after both have ended.
+  fn merge<T>(a: impl Stream<Item=T>, b: impl Stream<Item=T>) -> impl Stream<Item=T> {
+    let mut a = Box::pin(a);
+    let mut b = Box::pin(b);
+    let mut a_done = false;
+    let mut b_done = false;
+    /* Alternate which stream is polled first, so neither can starve the other. */
+    let mut prefer_a = true;
+    stream::poll_fn(move |cx| {
+      prefer_a = !prefer_a;
+      let item = if prefer_a {
+        poll_unless_done(a.as_mut(), &mut a_done, cx)
+          .or_else(|| poll_unless_done(b.as_mut(), &mut b_done, cx))
+      } else {
+        poll_unless_done(b.as_mut(), &mut b_done, cx)
+          .or_else(|| poll_unless_done(a.as_mut(), &mut a_done, cx))
+      };
+      match item {
+        Some(item) => Poll::Ready(Some(item)),
+        None if a_done && b_done => Poll::Ready(None),
+        None => Poll::Pending,
+      }
+    })
+  }
+
+  /// An item produced by [`Streaming::into_chunks`] or [`Streaming::into_lines`].
+  #[derive(Debug, Clone, PartialEq, Eq)]
+  pub enum StdioEvent<T> {
+    /// Output from the child process.
+    Output(T),
+    /// The exit status of the child process. This is always the last event, and is only
+    /// produced if the status is accepted by [`exe::Command::exit_policy`].
+    Exit(ExitStatus),
+  }
+
+  /// Output which is retained for the [`exe::ExitReport`] of a process which fails.
+  pub(crate) trait TailOutput {
+    /// Append this output to the tail of the stream it was read from.
+    fn record(&self, stdout: &mut exe::TailBuffer, stderr: &mut exe::TailBuffer);
+  }
+
+  impl TailOutput for StdioChunk {
+    fn record(&self, stdout: &mut exe::TailBuffer, stderr: &mut exe::TailBuffer) {
+      match self {
+        Self::Out(chunk) => stdout.push(chunk),
+        Self::Err(chunk) => stderr.push(chunk),
+      }
+    }
+  }
+
+  impl TailOutput for StdioLine {
+    fn record(&self, stdout: &mut exe::TailBuffer, stderr: &mut exe::TailBuffer) {
+      let (tail, line) = match self {
+        Self::Out(line) => (stdout, line),
+        Self::Err(line) => (stderr, line),
+      };
+      tail.push(line.as_bytes());
+      tail.push(b"\n");
+    }
+  }
+
+  /// Resolve once any child in `stages` which has not been seen to exit yet exits, so that its
+  /// elapsed time is recorded when it exits rather than once all output has been read. This never
+  /// resolves if every child has already exited.
+  async fn any_exited(stages: &mut [pipe::Stage]) -> io::Result<()> {
+    let mut waits: Vec<_> = stages
+      .iter_mut()
+      .filter(|stage| !stage.child.has_exited())
+      .map(|stage| Box::pin(stage.child.wait()))
+      .collect();
+    future::poll_fn(move |cx| {
+      for wait in waits.iter_mut() {
+        if let Poll::Ready(result) = wait.as_mut().poll(cx) {
+          return Poll::Ready(result.map(|_| ()));
+        }
+      }
+      Poll::Pending
+    })
+    .await
+  }
+
+  struct EventState<S> {
+    output: Pin<Box<S>>,
+    stages: Vec<pipe::Stage>,
+    interrupted: Pin<Box<dyn Future<Output=(usize, exe::Interrupt)>+Send>>,
+    stdout: exe::TailBuffer,
+    stderr: exe::TailBuffer,
+  }
+
+  enum Next<T> {
+    Output(Option<io::Result<T>>),
+    StageExited(io::Result<()>),
+    Exited(io::Result<Vec<ExitStatus>>),
+    Interrupted((usize, exe::Interrupt)),
+  }
+
+  impl<S, T> EventState<S>
+  where
+    S: Stream<Item=io::Result<T>>,
+    T: TailOutput,
+  {
+    async fn next_event(
+      mut self,
+    ) -> Option<(
+      Result<StdioEvent<T>, exe::CommandErrorWrapper>,
+      Option<Self>,
+    )> {
+      let Self {
+        output,
+        stages,
+        interrupted,
+        stdout,
+        stderr,
+      } = &mut self;
+
+      let next = loop {
+        let next = future::or(
+          async { Next::Output(output.next().await) },
+          future::or(
+            async { Next::Interrupted(interrupted.as_mut().await) },
+            async { Next::StageExited(any_exited(stages).await) },
+          ),
+        )
+        .await;
+        match next {
+          Next::StageExited(Ok(())) => continue,
+          Next::StageExited(Err(e)) => break Next::Exited(Err(e)),
+          next => break next,
+        }
+      };
+      let next = match next {
+        /* Once all output has been read, wait for every child to exit. */
+        Next::Output(None) => {
+          let exited = async {
+            let mut statuses = Vec::with_capacity(stages.len());
+            for stage in stages.iter_mut() {
+              match stage.child.wait().await {
+                Ok(status) => statuses.push(status),
+                Err(e) => return Next::Exited(Err(e)),
+              }
+            }
+            Next::Exited(Ok(statuses))
+          };
+          future::or(exited, async {
+            Next::Interrupted(interrupted.as_mut().await)
+          })
+          .await
+        },
+        next => next,
+      };
+
+      let command: &exe::Command = &stages.last().expect("there is always a stage").command;
+      match next {
+        Next::Output(Some(Ok(item))) => {
+          item.record(stdout, stderr);
+          Some((Ok(StdioEvent::Output(item)), Some(self)))
+        },
+        Next::Output(Some(Err(e))) => {
+          let e: exe::CommandError = e.into();
+          let e = e.command_with_context(command.clone(), "merging async streams".to_string());
+          Some((Err(e), None))
+        },
+        Next::Output(None) => unreachable!("end of output was handled above"),
+        Next::StageExited(_) => unreachable!("stage exits were handled above"),
+        Next::Exited(Err(e)) => {
+          let e: exe::CommandError = e.into();
+          let e = e.command_with_context(command.clone(), "waiting for async exit".to_string());
+          Some((Err(e), None))
+        },
+        Next::Exited(Ok(statuses)) => {
+          let policies = stages.iter().map(|stage| &stage.command.exit_policy);
+          let event = pipe::check_statuses(policies, &statuses)
+            .map(|()| StdioEvent::Exit(*statuses.last().expect("there is always a stage")))
+            .map_err(|(index, e)| {
+              e.command_with_context(
+                exe::Command::clone(&stages[index].command),
+                "checking async exit status".to_string(),
+              )
+              .with_report(exe::ExitReport {
+                status: statuses[index],
+                stdout: stdout.tail(),
+                stderr: stderr.tail(),
+                elapsed: Some(stages[index].child.elapsed()),
+              })
+            });
+          Some((event, None))
+        },
+        Next::Interrupted((index, interrupt)) => {
+          let e = match pipe::terminate_all(stages).await {
+            Ok(()) => interrupt.into_error(RawOutput::default()),
+            Err(e) => e.into(),
+          };
+          let e = e.command_with_context(
+            exe::Command::clone(&stages[index].command),
+            "tearing down async process".to_string(),
+          );
+          Some((Err(e), None))
+        },
+      }
+    }
+  }
+
+  /// A handle to write to the stdin of a child process.
+  ///
+  /// Any bytes from [`exe::Stdin::Bytes`] are written before anything else is sent. Dropping
+  /// this handle closes the pipe, but [`Self::close`] should be preferred to ensure all input
+  /// was flushed.
+  ///
+  /// This is an [`AsyncWrite`], so input can be copied from any reader. Closing it as a writer
+  /// also sends EOF:
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::path::PathBuf;
+  /// use futures_lite::{io, prelude::*};
+  /// use super_process::{fs, exe, stream::{Streamable, StdioEvent, StdioLine}};
+  ///
+  /// let command = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("cat"))),
+  ///   stdin: Some(exe::Stdin::Bytes(b"first\n".to_vec())),
+  ///   ..Default::default()
+  /// };
+  /// let mut streaming = command.invoke_streaming().unwrap();
+  /// let mut stdin = streaming.stdin.take().unwrap();
+  /// io::copy(io::Cursor::new(b"second\n"), &mut stdin).await.unwrap();
+  /// io::AsyncWriteExt::close(&mut stdin).await.unwrap();
+  ///
+  /// let events: Vec<_> = streaming.into_lines().try_collect().await.unwrap();
+  /// assert_eq!(events[..2], [
+  ///   StdioEvent::Output(StdioLine::Out("first".to_string())),
+  ///   StdioEvent::Output(StdioLine::Out("second".to_string())),
+  /// ]);
+  /// assert!(matches!(events[2], StdioEvent::Exit(status) if status.success()));
+  /// # }) // async
+  ///```
+  pub struct StdinSink {
+    /// This is [`None`] once the pipe has been closed.
+    pipe: Option<ChildStdin>,
+    pending: Vec<u8>,
+  }
+
+  impl StdinSink {
+    fn closed() -> io::Error {
+      io::Error::new(io::ErrorKind::BrokenPipe, "stdin was already closed")
+    }
+
+    fn pipe(&mut self) -> io::Result<Pin<&mut ChildStdin>> {
+      self.pipe.as_mut().map(Pin::new).ok_or_else(Self::closed)
+    }
+
+    /// Write out any bytes from [`exe::Stdin::Bytes`] which have not been written yet.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+      while !self.pending.is_empty() {
+        let pipe = self.pipe.as_mut().ok_or_else(Self::closed)?;
+        match futures_lite::ready!(Pin::new(pipe).poll_write(cx, &self.pending))? {
+          0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+          num_written => {
+            self.pending.drain(..num_written);
+          },
+        }
+      }
+      Poll::Ready(Ok(()))
+    }
+
+    /// Write all of `input` to the child and flush it.
+    pub async fn send(&mut self, input: &[u8]) -> io::Result<()> {
+      self.write_all(input).await?;
+      self.flush().await
+    }
+
+    /// Flush any remaining input and close the pipe, so that the child reads EOF.
+    pub async fn close(mut self) -> io::Result<()> {
+      futures_lite::AsyncWriteExt::close(&mut self).await
+    }
+
+    /// Like [`Self::close`], but ignore errors from the child closing its end of the pipe first.
+    async fn close_quietly(self) -> io::Result<()> {
+      match self.close().await {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+      }
+    }
+  }
+
+  impl AsyncWrite for StdinSink {
+    fn poll_write(
+      mut self: Pin<&mut Self>,
+      cx: &mut Context<'_>,
+      buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+      futures_lite::ready!(self.poll_pending(cx))?;
+      self.pipe()?.poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+      futures_lite::ready!(self.poll_pending(cx))?;
+      self.pipe()?.poll_flush(cx)
+    }
+
+    /// Flush any remaining input and close the pipe, so that the child reads EOF.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+      if self.pipe.is_none() {
+        return Poll::Ready(Ok(()));
+      }
+      futures_lite::ready!(self.poll_pending(cx))?;
+      futures_lite::ready!(self.pipe()?.poll_close(cx))?;
+      /* Closing the pipe only flushes it, so drop our end for the child to read EOF. */
+      self.pipe = None;
+      Poll::Ready(Ok(()))
+    }
+  }
+
+  /// A handle to the result an asynchronous invocation.
+  ///
+  /// With [`exe::Stdin::Piped`], this can drive an interactive session:
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::path::PathBuf;
+  /// use futures_lite::prelude::*;
+  /// use super_process::{fs, exe, stream::{Streamable, StdioEvent, StdioLine}};
+  ///
+  /// let command = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+  ///   stdin: Some(exe::Stdin::Piped),
+  ///   ..Default::default()
+  /// };
+  /// let mut streaming = command.invoke_streaming().unwrap();
+  /// let mut stdin = streaming.stdin.take().unwrap();
+  /// let mut events = Box::pin(streaming.into_lines());
+  ///
+  /// stdin.send(b"echo hello\n").await.unwrap();
+  /// assert_eq!(
+  ///   events.next().await.unwrap().unwrap(),
+  ///   StdioEvent::Output(StdioLine::Out("hello".to_string())),
+  /// );
+  /// stdin.send(b"echo goodbye >&2\n").await.unwrap();
+  /// assert_eq!(
+  ///   events.next().await.unwrap().unwrap(),
+  ///   StdioEvent::Output(StdioLine::Err("goodbye".to_string())),
+  /// );
+  ///
+  /// // Closing stdin sends EOF, which makes the shell exit.
+  /// stdin.close().await.unwrap();
+  /// match events.next().await.unwrap().unwrap() {
+  ///   StdioEvent::Exit(status) => assert!(status.success()),
+  ///   _ => unreachable!(),
+  /// }
+  /// # }) // async
+  ///```
+  pub struct Streaming {
+    /// The handle to the live child process.
+    pub child: exe::ManagedChild,
+    /// The stdin pipe, if [`exe::Stdin::Piped`] or [`exe::Stdin::Bytes`] was requested.
+    ///
+    /// If this is not taken before the output is consumed, any [`exe::Stdin::Bytes`] are
+    /// written and then the pipe is closed.
+    pub stdin: Option<StdinSink>,
+    /// The stdout stream, separated from the process handle.
+    pub stdout: ChildStdout,
+    /// The stderr stream, separated from the process handler.
+    pub stderr: ChildStderr,
+    /// The command being executed.
+    ///
+    /// For a [`Pipeline`], this is the last stage, which produces [`Self::stdout`].
+    pub command: exe::Command,
+    /// The earlier stages of a [`Pipeline`], which feed into [`Self::child`].
+    pub upstream: Vec<pipe::Stage>,
+    /// The stderr of every stage in [`Self::upstream`], sharing a single pipe.
+    pub upstream_stderr: Option<Async<std::io::PipeReader>>,
+  }
+
+  impl Streaming {
+    pub(crate) fn into_events<S, T>(
+      stages: Vec<pipe::Stage>,
+      stdin: Option<StdinSink>,
+      output: S,
+    ) -> impl Stream<Item=Result<StdioEvent<T>, exe::CommandErrorWrapper>>
+    where
+      S: Stream<Item=io::Result<T>>,
+      T: TailOutput,
+    {
+      /* Write any remaining input concurrently with reading output, surfacing only errors. */
+      let write_stdin = stream::once_future(async move {
+        match stdin {
+          Some(stdin) => stdin.close_quietly().await,
+          None => Ok(()),
+        }
+      })
+      .filter_map(|result| result.err().map(Err));
+      let output = merge(output, write_stdin);
+
+      let interrupted = pipe::any_interrupted(
+        stages
+          .iter()
+          .map(|stage| stage.command.termination.clone())
+          .collect(),
+      );
+      let state = EventState {
+        output: Box::pin(output),
+        stages,
+        interrupted,
+        stdout: exe::TailBuffer::default(),
+        stderr: exe::TailBuffer::default(),
+      };
+      let span = state
+        .stages
+        .last()
+        .expect("there is always a stage")
+        .child
+        .span()
+        .clone();
+      stream::unfold(Some(state), move |state| {
+        let span = span.clone();
+        async move {
+          let (event, state) = state?.next_event().instrument(span).await?;
+          Some((event.map_err(hook::failed), state))
+        }
+      })
+    }
+
+    /// Merge the stdout and stderr of this process into a stream of byte chunks, followed by
+    /// its exit status.
+    ///
+    /// The stream ends after the first error. The child is torn down according to
+    /// [`exe::Command::termination`].
+    pub fn into_chunks(
+      self,
+    ) -> impl Stream<Item=Result<StdioEvent<StdioChunk>, exe::CommandErrorWrapper>> {
+      let Self {
+        stdout,
+        stderr,
+        stdin,
+        child,
+        command,
+        mut upstream,
+        upstream_stderr,
+      } = self;
+      let output = merge(
+        read_chunks(stdout, StdioChunk::Out),
+        read_chunks(stderr, StdioChunk::Err),
+      );
+      let output = merge(
+        output,
+        stream::iter(upstream_stderr).flat_map(|stderr| read_chunks(stderr, StdioChunk::Err)),
+      );
+      upstream.push(pipe::Stage {
+        command: Arc::new(command),
+        child,
+      });
+      Self::into_events(upstream, stdin, output)
+    }
+
+    /// Merge the stdout and stderr of this process into a stream of lines, followed by its exit
+    /// status.
+    ///
+    /// The stream ends after the first error. The child is torn down according to
+    /// [`exe::Command::termination`].
+    pub fn into_lines(
+      self,
+    ) -> impl Stream<Item=Result<StdioEvent<StdioLine>, exe::CommandErrorWrapper>> {
+      let Self {
+        stdout,
+        stderr,
+        stdin,
+        child,
+        command,
+        mut upstream,
+        upstream_stderr,
+      } = self;
+      let output = merge(
+        BufReader::new(stdout)
+          .lines()
+          .map(|line| line.map(StdioLine::Out)),
+        BufReader::new(stderr)
+          .lines()
+          .map(|line| line.map(StdioLine::Err)),
+      );
+      let output = merge(
+        output,
+        stream::iter(upstream_stderr).flat_map(|stderr| {
+          BufReader::new(stderr)
+            .lines()
+            .map(|line| line.map(StdioLine::Err))
+        }),
+      );
+      upstream.push(pipe::Stage {
+        command: Arc::new(command),
+        child,
+      });
+      Self::into_events(upstream, stdin, output)
+    }
+
+    /// Pass every output event from `events` through `act`.
+    async fn exhaust_events<T, F, A>(
+      events: impl Stream<Item=Result<StdioEvent<T>, exe::CommandErrorWrapper>>,
+      command: exe::Command,
+      act: A,
+    ) -> Result<(), exe::CommandErrorWrapper>
+    where
+      F: Future<Output=Result<(), exe::CommandError>>,
+      A: Fn(T) -> F,
+    {
+      let mut events = Box::pin(events);
+      while let Some(event) = events.next().await {
+        match event? {
+          StdioEvent::Output(output) => act(output).await.map_err(|e| {
+            e.command_with_context(command.clone(), "merging async streams".to_string())
+          })?,
+          StdioEvent::Exit(_) => (),
+        }
+      }
+      Ok(())
+    }
+
+    /// Stream the output of this process through `act`, then analyze the exit status.
+    pub async fn exhaust_byte_streams_and_wait<F, A>(
+      self,
+      act: A,
+    ) -> Result<(), exe::CommandErrorWrapper>
+    where
+      F: Future<Output=Result<(), exe::CommandError>>,
+      A: Fn(StdioChunk) -> F,
+    {
+      let command = self.command.clone();
+      Self::exhaust_events(self.into_chunks(), command, act).await
+    }
+
+    /// Stream the output of this process through `act`, then analyze the exit status.
+    pub async fn exhaust_string_streams_and_wait<F, A>(
+      self,
+      act: A,
+    ) -> Result<(), exe::CommandErrorWrapper>
+    where
+      F: Future<Output=Result<(), exe::CommandError>>,
+      A: Fn(StdioLine) -> F,
+    {
+      let command = self.command.clone();
+      Self::exhaust_events(self.into_lines(), command, act).await
+    }
+
+    /// Copy over all stderr lines to our stderr, and stdout lines to our stdout.
+    async fn stdio_streams_callback(line: StdioLine) -> Result<(), exe::CommandError> {
+      match line {
+        StdioLine::Err(err) => {
+          let err = str::from_utf8(err.as_bytes()).expect("UTF8 DECODING STDERR FAILED");
+          eprintln!("{}", err);
+        },
+        StdioLine::Out(out) => {
+          let out = str::from_utf8(out.as_bytes()).expect("UTF8 DECODING STDOUT FAILED");
+          println!("{}", out);
+        },
+      }
+      Ok(())
+    }
+
+    /// Wait for the process to exit, printing lines of stdout and stderr to the terminal.
+    pub async fn wait(self) -> Result<(), exe::CommandErrorWrapper> {
+      self
+        .exhaust_string_streams_and_wait(Self::stdio_streams_callback)
+        .await?;
+      Ok(())
+    }
+  }
+
+  /// A chunk of either stdout or stderr from a subprocess.
+  #[derive(Debug, Clone, PartialEq, Eq)]
+  pub enum StdioChunk {
+    /// A chunk of stdout.
+    Out(Vec<u8>),
+    /// A chunk of stderr.
+    Err(Vec<u8>),
+  }
+
+  /// A line of either stdout or stderr from a subprocess.
+  #[derive(Debug, Clone, PartialEq, Eq)]
+  pub enum StdioLine {
+    /// A line of stdout.
+    Out(String),
+    /// A line of stderr.
+    Err(String),
+  }
+
+  /// Trait that defines "asynchronously" invokable processes.
+  pub trait Streamable {
+    /// Invoke a child process and return a handle to its output streams.
+    fn invoke_streaming(self) -> Result<Streaming, exe::CommandErrorWrapper>;
+  }
+
+  impl Streamable for exe::Command {
+    fn invoke_streaming(self) -> Result<Streaming, exe::CommandErrorWrapper> {
+      Pipeline::from(self).invoke_streaming()
+    }
+  }
+
+  impl Streamable for Pipeline {
+    fn invoke_streaming(self) -> Result<Streaming, exe::CommandErrorWrapper> {
+      let pending = match self.first().stdin {
+        Some(exe::Stdin::Bytes(ref bytes)) => bytes.clone(),
+        _ => Vec::new(),
+      };
+      let pipe::Spawned {
+        mut stages,
+        stdin,
+        stdout,
+        stderr,
+        upstream_stderr,
+      } = self.spawn(None).map_err(hook::failed)?;
+      let pipe::Stage { command, child } = stages.pop().expect("there is always a stage");
+      Ok(Streaming {
+        child,
+        stdin: stdin.map(|pipe| StdinSink {
+          pipe: Some(pipe),
+          pending,
+        }),
+        stdout,
+        stderr,
+        command: exe::Command::clone(&command),
+        upstream: stages,
+        upstream_stderr,
+      })
+    }
   }
 }
 
-/// Extend the concept of a "process" to include setup, to enable abstraction.
-pub mod base {
-  use super::*;
+/// Methods to connect processes into a pipeline, like `a | b | c` in a shell.
+///
+/// The stdout of each stage is connected to the stdin of the next with an OS pipe, so data flows
+/// between stages without passing through this process. The stderr of every stage is collected,
+/// and the pipeline fails if any stage fails, like `set -o pipefail`.
+///```
+/// # tokio_test::block_on(async {
+/// use std::path::PathBuf;
+/// use super_process::{fs, exe, pipe::Pipeline, sync::SyncInvocable};
+///
+/// let pipeline = Pipeline::from(exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("printf"))),
+///   argv: ["c\\nb\\na\\n"].as_ref().into(),
+///   ..Default::default()
+/// })
+/// .pipe(exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("sort"))),
+///   ..Default::default()
+/// })
+/// .pipe(exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("head"))),
+///   argv: ["-n", "2"].as_ref().into(),
+///   ..Default::default()
+/// });
+/// let output = pipeline.invoke().await.unwrap();
+/// assert_eq!(b"a\nb\n".as_ref(), &output.stdout);
+/// # }) // async
+///```
+///
+/// A failure in any stage is reported along with the exit status of every stage:
+///```
+/// # tokio_test::block_on(async {
+/// use std::path::PathBuf;
+/// use super_process::{fs, exe, pipe::Pipeline, stream::Streamable};
+///
+/// let pipeline = Pipeline::from(exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+///   argv: ["-c", "echo failing >&2; exit 3"].as_ref().into(),
+///   ..Default::default()
+/// })
+/// .pipe(exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("cat"))),
+///   ..Default::default()
+/// });
+/// match pipeline.invoke_streaming().unwrap().wait().await {
+///   Err(exe::CommandErrorWrapper {
+///     command,
+///     error: exe::CommandError::PipelineFailed { stage, statuses, error },
+///     ..
+///   }) => {
+///     assert_eq!(0, stage);
+///     let exe::Exe(fs::File(exe)) = command.exe;
+///     assert_eq!(PathBuf::from("sh"), exe);
+///     assert_eq!(vec![Some(3), Some(0)], statuses.iter().map(|s| s.code()).collect::<Vec<_>>());
+///     assert!(matches!(*error, exe::CommandError::NonZeroExit(3)));
+///   },
+///   _ => unreachable!(),
+/// }
+/// # }) // async
+///```
+pub mod pipe {
+  use super::{
+    exe, hook,
+    sync::{self, RawOutput},
+  };
 
+  use async_io::Async;
+  use async_process::{ChildStderr, ChildStdin, ChildStdout, ExitStatus, Stdio};
   use async_trait::async_trait;
-  use displaydoc::Display;
-  use thiserror::Error;
+  use futures_lite::{
+    future,
+    io::{AsyncRead, AsyncReadExt},
+  };
+  use tracing::Instrument;
 
-  use std::io;
+  use std::{future::Future, io, mem, pin::Pin, sync::Arc};
 
-  /// Errors which may occur during the execution of [`CommandBase::setup_command`].
-  #[derive(Debug, Display, Error)]
-  pub enum SetupError {
-    /// inner error: {0}
-    Inner(#[source] Box<SetupErrorWrapper>),
-    /// i/o error: {0}
-    Io(#[from] io::Error),
+  /// A sequence of commands, where the stdout of each is connected to the stdin of the next.
+  #[derive(Debug, Clone, Default)]
+  pub struct Pipeline {
+    /// The commands to execute, from left to right. The [`exe::Command::stdin`] of every stage
+    /// but the first is ignored.
+    pub stages: Vec<exe::Command>,
   }
 
-  impl SetupError {
-    /// Wrap a raw error with `context` to produced a wrapped error.
-    pub fn with_context(self, context: String) -> SetupErrorWrapper {
-      SetupErrorWrapper {
-        context,
-        error: self,
+  impl From<exe::Command> for Pipeline {
+    fn from(command: exe::Command) -> Self {
+      Self {
+        stages: vec![command],
       }
     }
   }
 
-  /// setup error ({context}): {error}
-  #[derive(Debug, Display, Error)]
-  pub struct SetupErrorWrapper {
-    /// Additional information about where the error occurred.
-    pub context: String,
-    /// The underlying error.
-    #[source]
-    pub error: SetupError,
+  impl Pipeline {
+    /// Connect the stdout of this pipeline to the stdin of `next`.
+    pub fn pipe(mut self, next: exe::Command) -> Self {
+      self.stages.push(next);
+      self
+    }
+
+    pub(crate) fn first(&self) -> &exe::Command {
+      match self.stages.first() {
+        Some(first) => first,
+        None => unreachable!(
+          "pipeline was executed before .stages was set; this can only occur using ::default()"
+        ),
+      }
+    }
+  }
+
+  /// A live process executing one stage of a [`Pipeline`].
+  #[derive(Debug)]
+  pub struct Stage {
+    /// The command being executed, shared with [`Self::child`].
+    pub command: Arc<exe::Command>,
+    /// The handle to the live child process.
+    pub child: exe::ManagedChild,
+  }
+
+  /// Stages which have been spawned by [`Pipeline::spawn`] so far.
+  ///
+  /// If a later stage fails to spawn, every stage spawned so far is killed on drop, along with its
+  /// process group if it leads one, then reaped in the background by [`async_process`].
+  struct Spawning(Vec<Stage>);
+
+  impl Drop for Spawning {
+    fn drop(&mut self) {
+      for stage in self.0.iter_mut() {
+        let _ = stage.child.signal_if_running(libc::SIGKILL);
+      }
+    }
+  }
+
+  pub(crate) struct Spawned {
+    pub stages: Vec<Stage>,
+    pub stdin: Option<ChildStdin>,
+    pub stdout: ChildStdout,
+    pub stderr: ChildStderr,
+    pub upstream_stderr: Option<Async<io::PipeReader>>,
+  }
+
+  pub(crate) fn stage_context(index: usize, num_stages: usize, context: &str) -> String {
+    if num_stages == 1 {
+      context.to_string()
+    } else {
+      format!(
+        "{} (stage {} of {} in pipeline)",
+        context, index, num_stages
+      )
+    }
+  }
+
+  impl Pipeline {
+    /// Spawn every stage, using `stdin` for the first stage if provided.
+    ///
+    /// The stdout and stderr of the last stage are piped to this process, along with the stderr
+    /// of every other stage.
+    pub(crate) fn spawn(self, stdin: Option<Stdio>) -> Result<Spawned, exe::CommandErrorWrapper> {
+      let first = self.first().clone();
+      let Self { stages: commands } = self;
+      let num_stages = commands.len();
+
+      /* Every upstream stage writes to the same stderr pipe, as they would to a terminal. */
+      let (upstream_stderr, stderr_writer) = if num_stages > 1 {
+        let (reader, writer) = io::pipe()
+          .and_then(|(reader, writer)| Ok((Async::new(reader)?, writer)))
+          .map_err(|e| e.into())
+          .map_err(|e: exe::CommandError| {
+            e.command_with_context(first, "creating stderr pipe for pipeline".to_string())
+          })?;
+        (Some(reader), Some(writer))
+      } else {
+        (None, None)
+      };
+
+      let mut stdin = stdin;
+      let mut previous_stdout: Option<io::PipeReader> = None;
+      let mut spawning = Spawning(Vec::with_capacity(num_stages));
+      for (index, command) in commands.into_iter().enumerate() {
+        let command = Arc::new(command);
+        let mut process = command.command()?;
+        let spawned = (|| {
+          match previous_stdout.take() {
+            Some(reader) => {
+              process.stdin(reader);
+            },
+            None => {
+              if let Some(stdin) = stdin.take() {
+                process.stdin(stdin);
+              }
+            },
+          }
+          match stderr_writer {
+            Some(ref stderr_writer) if index + 1 < num_stages => {
+              let (reader, writer) = io::pipe()?;
+              process.stdout(writer).stderr(stderr_writer.try_clone()?);
+              previous_stdout = Some(reader);
+            },
+            _ => {
+              process.stdout(Stdio::piped()).stderr(Stdio::piped());
+            },
+          }
+          exe::ManagedChild::spawn(&mut process, command.clone())
+        })();
+        /* Close our copies of the pipes handed to the child, so that each stage sees EOF when the
+         * one before it exits. */
+        drop(process);
+        let child = spawned
+          .map_err(|e| e.into())
+          .map_err(|e: exe::CommandError| {
+            e.command_with_context(
+              exe::Command::clone(&command),
+              stage_context(index, num_stages, "spawning process"),
+            )
+          })?;
+        spawning.0.push(Stage { command, child });
+      }
+      let mut stages = mem::take(&mut spawning.0);
+
+      let stdin = stages
+        .first_mut()
+        .and_then(|stage| stage.child.stdin.take());
+      let last = &mut stages.last_mut().expect("there is always a stage").child;
+      let stdout = last.stdout.take().expect("stdout should have been piped");
+      let stderr = last.stderr.take().expect("stderr should have been piped");
+      Ok(Spawned {
+        stages,
+        stdin,
+        stdout,
+        stderr,
+        upstream_stderr,
+      })
+    }
+
+    pub(crate) async fn invoke_with_input<R: AsyncRead+Unpin>(
+      self,
+      input: Option<R>,
+    ) -> Result<RawOutput, exe::CommandErrorWrapper> {
+      self.collect_output(input).await.map_err(hook::failed)
+    }
+
+    async fn collect_output<R: AsyncRead+Unpin>(
+      self,
+      input: Option<R>,
+    ) -> Result<RawOutput, exe::CommandErrorWrapper> {
+      let stdin = if input.is_some() {
+        Some(Stdio::piped())
+      } else if self.first().stdin.is_none() {
+        Some(Stdio::null())
+      } else {
+        None
+      };
+      let commands = self.stages.clone();
+      let num_stages = commands.len();
+      let Spawned {
+        mut stages,
+        stdin,
+        stdout,
+        stderr,
+        upstream_stderr,
+      } = self.spawn(stdin)?;
+      let span = stages
+        .last()
+        .expect("there is always a stage")
+        .child
+        .span()
+        .clone();
+
+      let mut stdout_buf: Vec<u8> = Vec::new();
+      let mut stderr_buf: Vec<u8> = Vec::new();
+      let mut upstream_stderr_buf: Vec<u8> = Vec::new();
+      let exited = async {
+        let read_upstream_stderr = async {
+          if let Some(mut upstream_stderr) = upstream_stderr {
+            upstream_stderr
+              .read_to_end(&mut upstream_stderr_buf)
+              .await?;
+          }
+          Ok(())
+        };
+        future::try_zip(
+          sync::communicate(
+            stdin,
+            stdout,
+            stderr,
+            input,
+            &mut stdout_buf,
+            &mut stderr_buf,
+          ),
+          read_upstream_stderr,
+        )
+        .await?;
+        let last = &mut stages.last_mut().expect("there is always a stage").child;
+        last.record_output(hook::OutputBytes {
+          stdout: stdout_buf.len(),
+          stderr: stderr_buf.len(),
+        });
+        let mut statuses = Vec::with_capacity(num_stages);
+        for stage in stages.iter_mut() {
+          statuses.push((stage.child.wait().await?, stage.child.elapsed()));
+        }
+        io::Result::Ok(Ok(statuses))
+      };
+      let interrupted = async {
+        let termination = commands.iter().map(|c| c.termination.clone()).collect();
+        Ok(Err(any_interrupted(termination).await))
+      };
+      let result = future::or(exited, interrupted).instrument(span).await;
+
+      let waited = match result {
+        Ok(Ok(statuses)) => Ok(statuses),
+        Ok(Err((index, interrupt))) => {
+          Err((index, terminate_all(&mut stages).await.map(|()| interrupt)))
+        },
+        Err(e) => Err((num_stages - 1, Err(e))),
+      };
+      upstream_stderr_buf.extend(stderr_buf);
+      let mut output = RawOutput {
+        stdout: stdout_buf,
+        stderr: upstream_stderr_buf,
+        status: None,
+      };
+      let statuses = waited.map_err(|(index, e)| {
+        let e = match e {
+          Ok(interrupt) => interrupt.into_error(output.clone()),
+          Err(e) => e.into(),
+        };
+        e.command_with_context(
+          commands[index].clone(),
+          stage_context(index, num_stages, "waiting for output"),
+        )
+      })?;
+
+      let (statuses, elapsed): (Vec<_>, Vec<_>) = statuses.into_iter().unzip();
+      output.status = statuses.last().copied();
+      let policies = commands.iter().map(|command| &command.exit_policy);
+      check_statuses(policies, &statuses).map_err(|(index, e)| {
+        output.failed(
+          e,
+          commands[index].clone(),
+          statuses[index],
+          Some(elapsed[index]),
+        )
+      })?;
+      Ok(output)
+    }
+  }
+
+  /// Check the exit status of every stage against its exit policy, reporting the rightmost
+  /// failure like `set -o pipefail`.
+  pub(crate) fn check_statuses<'a>(
+    policies: impl Iterator<Item=&'a exe::ExitPolicy>,
+    statuses: &[ExitStatus],
+  ) -> Result<(), (usize, exe::CommandError)> {
+    let failure = policies
+      .zip(statuses)
+      .enumerate()
+      .filter_map(|(index, (policy, status))| policy.check(*status).err().map(|e| (index, e)))
+      .last();
+    match failure {
+      None => Ok(()),
+      Some(failure) if statuses.len() == 1 => Err(failure),
+      Some((stage, error)) => Err((stage, exe::CommandError::PipelineFailed {
+        stage,
+        statuses: statuses.to_vec(),
+        error: Box::new(error),
+      })),
+    }
+  }
+
+  /// Resolve with the index of the first stage which should be torn down, and why.
+  pub(crate) fn any_interrupted(
+    terminations: Vec<exe::Termination>,
+  ) -> Pin<Box<dyn Future<Output=(usize, exe::Interrupt)>+Send>> {
+    let mut any: Pin<Box<dyn Future<Output=(usize, exe::Interrupt)>+Send>> =
+      Box::pin(future::pending());
+    for (index, termination) in terminations.into_iter().enumerate() {
+      any = Box::pin(future::or(any, async move {
+        (index, termination.interrupted().await)
+      }));
+    }
+    any
+  }
+
+  /// Tear down every stage which has not exited according to its own
+  /// [`exe::Command::termination`].
+  ///
+  /// Every stage is torn down even if signalling one of them fails, and the first error is
+  /// returned afterwards.
+  pub(crate) async fn terminate_all(stages: &mut [Stage]) -> io::Result<()> {
+    let mut result = Ok(());
+    for Stage { command, child } in stages.iter_mut() {
+      if child.has_exited() && command.process_group == exe::ProcessGroup::Inherit {
+        continue;
+      }
+      let terminated = command.termination.terminate(child).await;
+      if let (Ok(()), Err(e)) = (&result, terminated) {
+        result = Err(e);
+      }
+    }
+    result
   }
 
-  /// Declare higher-level operations which desugar to command lines by implementing this trait.
   #[async_trait]
-  pub trait CommandBase {
-    /// Generate a command line from the given object.
-    async fn setup_command(self) -> Result<exe::Command, SetupError>;
+  impl sync::SyncInvocable for Pipeline {
+    async fn invoke(self) -> Result<RawOutput, exe::CommandErrorWrapper> {
+      let input = match self.first().stdin {
+        Some(exe::Stdin::Bytes(ref bytes)) => Some(futures_lite::io::Cursor::new(bytes.clone())),
+        _ => None,
+      };
+      self.invoke_with_input(input).await
+    }
+
+    async fn invoke_with_stdin<R>(self, stdin: R) -> Result<RawOutput, exe::CommandErrorWrapper>
+    where R: AsyncRead+Unpin+Send+'static {
+      self.invoke_with_input(Some(stdin)).await
+    }
   }
 }
 
-/// Methods to execute a process "synchronously", i.e. waiting until it has exited.
+/// Methods to invoke processes from synchronous code, e.g. a `build.rs`.
 ///
+/// [`blocking::BlockingInvocable`] mirrors [`crate::sync::SyncInvocable`] for [`exe::Command`] and
+/// [`pipe::Pipeline`], producing the same [`sync::RawOutput`] and [`exe::CommandErrorWrapper`].
+/// Children are spawned with [`std::process`], and their input and output are copied on separate
+/// threads so that no pipe can fill up and block the child. No async runtime is required.
 ///```
-/// # tokio_test::block_on(async {
-/// use std::{str, path::PathBuf};
-/// use super_process::{fs, exe, sync::SyncInvocable};
+/// use std::path::PathBuf;
+/// use super_process::{blocking::BlockingInvocable, fs, exe, pipe::Pipeline};
 ///
 /// let command = exe::Command {
-///   exe: exe::Exe(fs::File(PathBuf::from("echo"))),
-///   argv: ["hey"].as_ref().into(),
+///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+///   argv: ["-c", "cat; echo err >&2"].as_ref().into(),
 ///   ..Default::default()
 /// };
+/// let output = command.invoke_blocking_with_stdin(b"hello\n".as_ref()).unwrap();
+/// assert_eq!(b"hello\n".as_ref(), &output.stdout);
+/// assert_eq!(b"err\n".as_ref(), &output.stderr);
 ///
-/// // Spawn the child process and wait for it to end.
-/// let output = command.clone().invoke().await.expect("sync subprocess failed");
-/// // Parse stdout into utf8...
-/// let hey = str::from_utf8(&output.stdout).expect("utf8 decoding failed")
-///   // ...and strip the trailing newline.
-///   .strip_suffix("\n")
-///   .expect("trailing newline not found");
-/// assert_eq!(hey, "hey");
-/// # }) // async
+/// let pipeline = Pipeline::from(exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("printf"))),
+///   argv: ["b\\na\\n"].as_ref().into(),
+///   ..Default::default()
+/// })
+/// .pipe(exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("sort"))),
+///   ..Default::default()
+/// });
+/// assert_eq!(b"a\nb\n".as_ref(), &pipeline.invoke_blocking().unwrap().stdout);
 ///```
-pub mod sync {
-  use super::exe;
+///
+/// [`exe::Command::termination`] is respected as well:
+///```
+/// use std::{path::PathBuf, time::Duration};
+/// use super_process::{blocking::BlockingInvocable, fs, exe};
+///
+/// let command = exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+///   argv: ["-c", "echo started; sleep 30"].as_ref().into(),
+///   termination: exe::Termination {
+///     timeout: Some(Duration::from_millis(500)),
+///     ..Default::default()
+///   },
+///   ..Default::default()
+/// };
+/// match command.invoke_blocking() {
+///   Err(exe::CommandErrorWrapper {
+///     error: exe::CommandError::TimedOut { output, .. },
+///     ..
+///   }) => assert_eq!(b"started\n".as_ref(), &output.stdout),
+///   _ => unreachable!(),
+/// }
+///
+/// // A background process holding the child's output open is bounded by the same timeout.
+/// let command = exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+///   argv: ["-c", "sleep 5 & echo started"].as_ref().into(),
+///   termination: exe::Termination {
+///     timeout: Some(Duration::from_millis(500)),
+///     ..Default::default()
+///   },
+///   ..Default::default()
+/// };
+/// match command.invoke_blocking() {
+///   Err(exe::CommandErrorWrapper {
+///     error: exe::CommandError::TimedOut { output, .. },
+///     ..
+///   }) => assert_eq!(b"started\n".as_ref(), &output.stdout),
+///   _ => unreachable!(),
+/// }
+///```
+pub mod blocking {
+  use super::{
+    exe, hook,
+    pipe::{self, Pipeline},
+    sync::RawOutput,
+  };
+
+  use std::{
+    io::{self, Read},
+    mem,
+    process::{Child, ExitStatus, Stdio},
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+  };
+
+  /// Trait that defines processes which can be invoked from synchronous code.
+  pub trait BlockingInvocable {
+    /// Invoke a child process and block until it completes while slurping its output.
+    fn invoke_blocking(self) -> Result<RawOutput, exe::CommandErrorWrapper>;
+
+    /// Invoke a child process, copying all of `stdin` into its standard input while slurping its
+    /// output.
+    ///
+    /// This overrides any [`exe::Stdin`] configured for the child.
+    fn invoke_blocking_with_stdin<R>(self, stdin: R) -> Result<RawOutput, exe::CommandErrorWrapper>
+    where R: Read+Send+'static;
+  }
+
+  impl BlockingInvocable for exe::Command {
+    fn invoke_blocking(self) -> Result<RawOutput, exe::CommandErrorWrapper> {
+      Pipeline::from(self).invoke_blocking()
+    }
+
+    fn invoke_blocking_with_stdin<R>(self, stdin: R) -> Result<RawOutput, exe::CommandErrorWrapper>
+    where R: Read+Send+'static {
+      Pipeline::from(self).invoke_blocking_with_stdin(stdin)
+    }
+  }
 
-  use async_trait::async_trait;
+  impl BlockingInvocable for Pipeline {
+    fn invoke_blocking(self) -> Result<RawOutput, exe::CommandErrorWrapper> {
+      let input = match self.first().stdin {
+        Some(exe::Stdin::Bytes(ref bytes)) => Some(io::Cursor::new(bytes.clone())),
+        _ => None,
+      };
+      invoke_with_input(self, input).map_err(hook::failed)
+    }
 
-  use std::{process, str};
+    fn invoke_blocking_with_stdin<R>(self, stdin: R) -> Result<RawOutput, exe::CommandErrorWrapper>
+    where R: Read+Send+'static {
+      invoke_with_input(self, Some(stdin)).map_err(hook::failed)
+    }
+  }
 
-  /// The slurped streams for a synchronously-invoked process, as raw bytes.
-  #[derive(Debug, Clone)]
-  #[allow(missing_docs)]
-  pub struct RawOutput {
-    pub stdout: Vec<u8>,
-    pub stderr: Vec<u8>,
+  /// How often to check [`exe::Termination::cancel`] while waiting for children to exit.
+  const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+  /// A spawned stage of a pipeline. The child itself is owned by a thread waiting on its exit.
+  struct Stage {
+    command: exe::Command,
+    pid: u32,
+    started: Instant,
+    span: tracing::Span,
+    exit: Option<(ExitStatus, Duration)>,
   }
 
-  impl RawOutput {
-    /// Parse the process's exit status with [`exe::CommandError::analyze_exit_status`].
-    pub fn extract(
-      command: exe::Command,
-      output: process::Output,
-    ) -> Result<Self, exe::CommandErrorWrapper> {
-      let process::Output {
-        status,
-        stdout,
-        stderr,
-      } = output;
+  impl Stage {
+    fn signal(&self, signal: i32) -> io::Result<()> {
+      let leads_group = self.command.process_group != exe::ProcessGroup::Inherit;
+      exe::signal_process(self.pid, leads_group, signal)
+    }
 
-      let output = Self { stdout, stderr };
-      exe::CommandError::analyze_exit_status(status).map_err(|e| {
-        let output_msg: String = match output.clone().decode(command.clone()) {
-          Ok(decoded) => format!("(utf-8 decoded) {:?}", decoded),
-          Err(_) => format!("(could not decode) {:?}", &output),
-        };
-        e.command_with_context(
-          command,
-          format!("when analyzing exit status for output {}", output_msg),
-        )
-      })?;
+    fn record(&mut self, status: io::Result<ExitStatus>) -> io::Result<()> {
+      self.exit = Some((status?, self.started.elapsed()));
+      Ok(())
+    }
 
-      Ok(output)
+    fn interrupted(&self) -> Option<exe::Interrupt> {
+      let exe::Termination {
+        timeout, cancel, ..
+      } = &self.command.termination;
+      if cancel.as_ref().is_some_and(|cancel| cancel.is_cancelled()) {
+        return Some(exe::Interrupt::Cancelled);
+      }
+      timeout
+        .filter(|timeout| self.started.elapsed() >= *timeout)
+        .map(exe::Interrupt::TimedOut)
     }
 
-    /// Decode the output streams of this process, with the invoking `command` provided for
-    /// error context.
-    pub fn decode(self, command: exe::Command) -> Result<DecodedOutput, exe::CommandErrorWrapper> {
-      let Self { stdout, stderr } = &self;
-      let stdout = str::from_utf8(stdout)
+    /// How long until [`Self::interrupted`] should be checked again, if ever.
+    fn next_check(&self) -> Option<Duration> {
+      let exe::Termination {
+        timeout, cancel, ..
+      } = &self.command.termination;
+      let timeout = timeout.map(|timeout| timeout.saturating_sub(self.started.elapsed()));
+      let cancel = cancel.as_ref().map(|_| CANCEL_POLL_INTERVAL);
+      timeout.into_iter().chain(cancel).min()
+    }
+  }
+
+  type Exits = mpsc::Receiver<(usize, io::Result<ExitStatus>)>;
+
+  /// Results from the threads copying input and output, each sent once it finishes.
+  type Finished = mpsc::Receiver<io::Result<()>>;
+
+  /// Children which have been spawned but not yet handed to the threads waiting on their exit.
+  ///
+  /// If spawning a later stage fails, every child spawned so far is killed and reaped on drop,
+  /// along with its process group if it leads one.
+  #[derive(Default)]
+  struct Spawned(Vec<(Child, bool)>);
+
+  impl Drop for Spawned {
+    fn drop(&mut self) {
+      for (child, leads_group) in self.0.iter_mut() {
+        let _ = exe::signal_process(child.id(), *leads_group, libc::SIGKILL);
+        let _ = child.wait();
+      }
+    }
+  }
+
+  /// Copies everything from a reader into a buffer on a separate thread.
+  struct Drain {
+    buf: Arc<Mutex<Vec<u8>>>,
+  }
+
+  impl Drain {
+    fn spawn(mut reader: impl Read+Send+'static, finished: mpsc::Sender<io::Result<()>>) -> Self {
+      let buf: Arc<Mutex<Vec<u8>>> = Arc::default();
+      thread::spawn({
+        let buf = buf.clone();
+        move || {
+          let mut chunk = [0; 8192];
+          let result = loop {
+            match reader.read(&mut chunk) {
+              Ok(0) => break Ok(()),
+              Ok(n) => buf
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .extend_from_slice(&chunk[..n]),
+              Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+              Err(e) => break Err(e),
+            }
+          };
+          let _ = finished.send(result);
+        }
+      });
+      Self { buf }
+    }
+
+    /// Whatever has been read so far, without waiting for EOF.
+    fn snapshot(&self) -> Vec<u8> {
+      self
+        .buf
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+    }
+
+    /// Everything which was read, once the thread has reported that it finished.
+    fn take(self) -> Vec<u8> {
+      mem::take(&mut *self.buf.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+  }
+
+  fn invoke_with_input<R: Read+Send+'static>(
+    pipeline: Pipeline,
+    input: Option<R>,
+  ) -> Result<RawOutput, exe::CommandErrorWrapper> {
+    let Pipeline { stages: commands } = pipeline;
+    let num_stages = commands.len();
+
+    /* Every upstream stage writes to the same stderr pipe, as they would to a terminal. */
+    let (upstream_stderr, stderr_writer) = if num_stages > 1 {
+      let (reader, writer) = io::pipe()
         .map_err(|e| e.into())
         .map_err(|e: exe::CommandError| {
           e.command_with_context(
-            command.clone(),
-            format!("when decoding stdout from {:?}", &self),
+            commands[0].clone(),
+            "creating stderr pipe for pipeline".to_string(),
           )
-        })?
-        .to_string();
-      let stderr = str::from_utf8(stderr)
-        .map_err(|e| e.into())
-        .map_err(|e: exe::CommandError| {
-          e.command_with_context(command, format!("when decoding stderr from {:?}", &self))
-        })?
-        .to_string();
-      Ok(DecodedOutput { stdout, stderr })
+        })?;
+      (Some(reader), Some(writer))
+    } else {
+      (None, None)
+    };
+
+    let mut input = input;
+    let mut previous_stdout: Option<io::PipeReader> = None;
+    let mut spawned_children = Spawned::default();
+    let mut stages: Vec<Stage> = Vec::with_capacity(num_stages);
+    for (index, command) in commands.into_iter().enumerate() {
+      let (mut process, stdin) = command.std_command()?;
+      let spawned = (|| {
+        match previous_stdout.take() {
+          Some(reader) => {
+            process.stdin(reader);
+          },
+          None if input.is_some() => {
+            process.stdin(Stdio::piped());
+          },
+          None => {
+            process.stdin(stdin.unwrap_or_else(Stdio::null));
+          },
+        }
+        match stderr_writer {
+          Some(ref stderr_writer) if index + 1 < num_stages => {
+            let (reader, writer) = io::pipe()?;
+            process.stdout(writer).stderr(stderr_writer.try_clone()?);
+            previous_stdout = Some(reader);
+          },
+          _ => {
+            process.stdout(Stdio::piped()).stderr(Stdio::piped());
+          },
+        }
+        process.spawn()
+      })();
+      /* Close our copies of the pipes handed to the child, so that each stage sees EOF when the
+       * one before it exits. */
+      drop(process);
+      let child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+          let e: exe::CommandError = e.into();
+          return Err(e.command_with_context(
+            command,
+            pipe::stage_context(index, num_stages, "spawning process"),
+          ));
+        },
+      };
+      let pid = child.id();
+      stages.push(Stage {
+        span: hook::spawned(&command, pid),
+        started: Instant::now(),
+        pid,
+        exit: None,
+        command,
+      });
+      let leads_group = stages[index].command.process_group != exe::ProcessGroup::Inherit;
+      spawned_children.0.push((child, leads_group));
+    }
+    drop(stderr_writer);
+    let mut children: Vec<Child> = mem::take(&mut spawned_children.0)
+      .into_iter()
+      .map(|(child, _)| child)
+      .collect();
+
+    let (finished_sender, finished) = mpsc::channel();
+    let child_stdin = children[0].stdin.take();
+    let last = children.last_mut().expect("there is always a stage");
+    let stdout = Drain::spawn(
+      last.stdout.take().expect("stdout should have been piped"),
+      finished_sender.clone(),
+    );
+    let stderr = Drain::spawn(
+      last.stderr.take().expect("stderr should have been piped"),
+      finished_sender.clone(),
+    );
+    let upstream_stderr =
+      upstream_stderr.map(|upstream_stderr| Drain::spawn(upstream_stderr, finished_sender.clone()));
+    let num_threads = if upstream_stderr.is_some() { 4 } else { 3 };
+    thread::spawn(move || {
+      let write_stdin = || {
+        if let (Some(mut child_stdin), Some(mut input)) = (child_stdin, input.take()) {
+          match io::copy(&mut input, &mut child_stdin) {
+            /* The child may exit without reading all of its input, which is not our problem. */
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+            result => {
+              result?;
+            },
+          }
+          /* Dropping the handle closes the pipe, which signals EOF. */
+        }
+        io::Result::Ok(())
+      };
+      let _ = finished_sender.send(write_stdin());
+    });
+
+    let (exit_sender, exits) = mpsc::channel();
+    for (index, mut child) in children.into_iter().enumerate() {
+      let exit_sender = exit_sender.clone();
+      thread::spawn(move || {
+        let _ = exit_sender.send((index, child.wait()));
+      });
+    }
+    drop(exit_sender);
+
+    let waited = wait_all(&mut stages, &exits)
+      .and_then(|()| finish_io(&stages, &finished, num_threads));
+    let output = match waited {
+      Ok(()) => {
+        let mut stderr_buf = upstream_stderr.map(Drain::take).unwrap_or_default();
+        stderr_buf.extend(stderr.take());
+        Ok(RawOutput {
+          stdout: stdout.take(),
+          stderr: stderr_buf,
+          status: None,
+        })
+      },
+      /* Descendants of a torn-down child may still hold its pipes open, so don't wait for EOF. */
+      Err((index, e)) => {
+        let mut stderr_buf = upstream_stderr
+          .map(|upstream_stderr| upstream_stderr.snapshot())
+          .unwrap_or_default();
+        stderr_buf.extend(stderr.snapshot());
+        let output = RawOutput {
+          stdout: stdout.snapshot(),
+          stderr: stderr_buf,
+          status: None,
+        };
+        let e = match e {
+          Ok(interrupt) => interrupt.into_error(output),
+          Err(e) => e.into(),
+        };
+        Err((index, e))
+      },
+    };
+    for (index, stage) in stages.iter().enumerate() {
+      if let Some((status, elapsed)) = stage.exit {
+        let output = match output {
+          Ok(ref output) if index + 1 == num_stages => Some(hook::OutputBytes {
+            stdout: output.stdout.len(),
+            stderr: output.stderr.len(),
+          }),
+          _ => None,
+        };
+        hook::exited(&stage.span, &hook::ExitEvent {
+          command: &stage.command,
+          pid: stage.pid,
+          status,
+          elapsed,
+          output,
+        });
+      }
     }
+    let mut output = output.map_err(|(index, e): (usize, exe::CommandError)| {
+      e.command_with_context(
+        stages[index].command.clone(),
+        pipe::stage_context(index, num_stages, "waiting for output"),
+      )
+    })?;
+
+    let (statuses, elapsed): (Vec<_>, Vec<_>) = stages
+      .iter()
+      .map(|stage| stage.exit.expect("every stage has exited"))
+      .unzip();
+    output.status = statuses.last().copied();
+    let policies = stages.iter().map(|stage| &stage.command.exit_policy);
+    pipe::check_statuses(policies, &statuses).map_err(|(index, e)| {
+      output.failed(
+        e,
+        stages[index].command.clone(),
+        statuses[index],
+        Some(elapsed[index]),
+      )
+    })?;
+    Ok(output)
   }
 
-  /// The slurped streams for a synchronously-invoked process, after UTF-8 decoding.
-  #[derive(Debug, Clone)]
-  #[allow(missing_docs)]
-  pub struct DecodedOutput {
-    pub stdout: String,
-    pub stderr: String,
+  /// Wait for every stage to exit, tearing them all down if any stage is interrupted.
+  fn wait_all(
+    stages: &mut [Stage],
+    exits: &Exits,
+  ) -> Result<(), (usize, io::Result<exe::Interrupt>)> {
+    while stages.iter().any(|stage| stage.exit.is_none()) {
+      let interrupted = stages
+        .iter()
+        .enumerate()
+        .find_map(|(index, stage)| stage.interrupted().map(|interrupt| (index, interrupt)));
+      if let Some((index, interrupt)) = interrupted {
+        return Err((index, terminate_all(stages, exits).map(|()| interrupt)));
+      }
+      let received = match stages.iter().filter_map(Stage::next_check).min() {
+        Some(wait) => exits.recv_timeout(wait),
+        None => exits
+          .recv()
+          .map_err(|mpsc::RecvError| mpsc::RecvTimeoutError::Disconnected),
+      };
+      match received {
+        Ok((index, status)) => stages[index].record(status).map_err(|e| (index, Err(e)))?,
+        Err(mpsc::RecvTimeoutError::Timeout) => (),
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+          unreachable!("every waiting thread sends an exit status")
+        },
+      }
+    }
+    Ok(())
   }
 
-  /// Trait that defines "synchronously" invokable processes.
-  #[async_trait]
-  pub trait SyncInvocable {
-    /// Invoke a child process and wait on it to complete while slurping its output.
-    async fn invoke(self) -> Result<RawOutput, exe::CommandErrorWrapper>;
+  /// Wait for every thread copying input or output to finish.
+  ///
+  /// Descendants of a child may hold its pipes open after it exits, so each stage's
+  /// [`exe::Termination`] still applies here.
+  fn finish_io(
+    stages: &[Stage],
+    finished: &Finished,
+    mut remaining: usize,
+  ) -> Result<(), (usize, io::Result<exe::Interrupt>)> {
+    let last = stages.len() - 1;
+    while remaining > 0 {
+      let interrupted = stages
+        .iter()
+        .enumerate()
+        .find_map(|(index, stage)| stage.interrupted().map(|interrupt| (index, Ok(interrupt))));
+      if let Some(interrupted) = interrupted {
+        return Err(interrupted);
+      }
+      let received = match stages.iter().filter_map(Stage::next_check).min() {
+        Some(wait) => finished.recv_timeout(wait),
+        None => finished
+          .recv()
+          .map_err(|mpsc::RecvError| mpsc::RecvTimeoutError::Disconnected),
+      };
+      match received {
+        Ok(result) => {
+          result.map_err(|e| (last, Err(e)))?;
+          remaining -= 1;
+        },
+        Err(mpsc::RecvTimeoutError::Timeout) => (),
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+          unreachable!("every copying thread sends its result")
+        },
+      }
+    }
+    Ok(())
   }
 
-  #[async_trait]
-  impl SyncInvocable for exe::Command {
-    async fn invoke(self) -> Result<RawOutput, exe::CommandErrorWrapper> {
-      let mut command = self.clone().command();
-      let output =
-        command
-          .output()
-          .await
-          .map_err(|e| e.into())
-          .map_err(|e: exe::CommandError| {
-            e.command_with_context(self.clone(), "waiting for output".to_string())
-          })?;
-      let output = RawOutput::extract(self, output)?;
-      Ok(output)
+  /// Tear down every stage which has not exited according to its own
+  /// [`exe::Command::termination`]: send `SIGTERM`, then `SIGKILL` after the grace period.
+  fn terminate_all(stages: &mut [Stage], exits: &Exits) -> io::Result<()> {
+    for index in 0..stages.len() {
+      if stages[index].exit.is_some() {
+        continue;
+      }
+      stages[index].signal(libc::SIGTERM)?;
+      let deadline = Instant::now() + stages[index].command.termination.grace_period;
+      let mut killed = false;
+      while stages[index].exit.is_none() {
+        let (exited, status) = if killed {
+          exits
+            .recv()
+            .expect("every waiting thread sends an exit status")
+        } else {
+          match exits.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(received) => received,
+            Err(_) => {
+              stages[index].signal(libc::SIGKILL)?;
+              killed = true;
+              continue;
+            },
+          }
+        };
+        stages[exited].record(status)?;
+      }
     }
+    Ok(())
   }
 }
 
-/// Methods to execute a process in an "asynchronous" or "streaming" fashion.
-///
-/// **TODO: define a generic stream type like the `Emission` trait in `learning-progress-bar`, then
-/// express the stdio lines stream in terms of the stdio byte chunks stream!** We avoid doing that
-/// here because we expect using a [`BufReader`](futures_lite::io::BufReader) to produce
-/// [`StdioLine`](stream::StdioLine)s will be more efficient and cleaner than manually
-/// implementing a `BufReader` with `async-channel` or something.
+/// Methods to execute a process attached to a pseudo-terminal.
 ///
+/// Many programs check whether their output is a terminal in order to decide whether to emit
+/// colors, draw progress bars, or line-buffer their output. [`pty::PtyStreamable::invoke_pty`]
+/// makes a child process believe it is running interactively, and merges all of its output into a
+/// single stream of [`stream::StdioChunk::Out`]s.
 ///```
 /// # tokio_test::block_on(async {
 /// use std::path::PathBuf;
-/// use futures_lite::io::AsyncReadExt;
-/// use super_process::{fs, exe, stream::Streamable};
+/// use futures_lite::prelude::*;
+/// use super_process::{fs, exe, pty::{self, PtyStreamable}, stream::{StdioChunk, StdioEvent}};
 ///
 /// let command = exe::Command {
-///   exe: exe::Exe(fs::File(PathBuf::from("echo"))),
-///   argv: ["hey"].as_ref().into(),
+///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+///   argv: ["-c", "test -t 1 && echo tty; read _; stty size"].as_ref().into(),
 ///   ..Default::default()
 /// };
+/// let mut streaming = command
+///   .invoke_pty(pty::PtyOptions {
+///     size: pty::PtySize { rows: 30, cols: 100 },
+///     ..Default::default()
+///   })
+///   .unwrap();
 ///
-/// // Spawn the child process and stream its output, ignoring stderr for now.
-/// let mut streaming = command.invoke_streaming().expect("streaming subprocess failed");
-/// // Slurp stdout all at once into a string.
-/// let mut out: String = "".to_string();
-/// streaming.stdout.read_to_string(&mut out).await.expect("reading stdout failed");
+/// // Resizing the terminal notifies the child with SIGWINCH.
+/// let input = streaming.input.take().unwrap();
+/// input.resize(pty::PtySize { rows: 40, cols: 120 }).unwrap();
+/// input.send(b"\n").await.unwrap();
 ///
-/// // Now verify the process exited successfully.
-/// streaming.wait().await.expect("streaming command should have succeeded");
+/// let mut output: Vec<u8> = Vec::new();
+/// let mut events = Box::pin(streaming.into_chunks());
+/// while let Some(event) = events.next().await {
+///   match event.unwrap() {
+///     StdioEvent::Output(StdioChunk::Out(chunk)) => output.extend(chunk),
+///     StdioEvent::Output(StdioChunk::Err(_)) => unreachable!("all output is merged"),
+///     StdioEvent::Exit(status) => assert!(status.success()),
+///   }
+/// }
+/// let output = String::from_utf8(output).unwrap();
+/// // Input is echoed back by the terminal, possibly before the child writes anything.
+/// assert!(output.contains("tty\r\n"));
+/// assert!(output.ends_with("40 120\r\n"));
 ///
-/// // Validate we get the same output streaming.
-/// let hey = out.strip_suffix("\n").unwrap();
-/// assert!(hey == "hey");
+/// // The terminal is the child's stdin, so other stdin configuration is rejected.
+/// let command = exe::Command {
+///   exe: exe::Exe(fs::File(PathBuf::from("cat"))),
+///   stdin: Some(exe::Stdin::Bytes(b"hey".to_vec())),
+///   ..Default::default()
+/// };
+/// assert!(command.invoke_pty(pty::PtyOptions::default()).is_err());
 /// # }) // async
 ///```
-pub mod stream {
-  use super::exe;
+pub mod pty {
+  use super::{
+    exe, hook, pipe,
+    stream::{self, StdioChunk, StdioEvent, Streaming},
+  };
 
-  use async_process::{self, Child, ChildStderr, ChildStdout, Stdio};
-  use futures_lite::{io::BufReader, prelude::*};
+  use async_io::Async;
+  use async_process::unix::CommandExt;
+  use futures_lite::prelude::*;
 
-  use std::{future::Future, str};
+  use std::{
+    fs::File,
+    io, mem,
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    pin::Pin,
+    process::Stdio,
+    ptr,
+    sync::Arc,
+    task::{Context, Poll},
+  };
 
-  /// A handle to the result an asynchronous invocation.
-  pub struct Streaming {
-    /// The handle to the live child process (live until [`Child::output`] is called).
-    pub child: Child,
-    /// The stdout stream, separated from the process handle.
-    pub stdout: ChildStdout,
-    /// The stderr stream, separated from the process handler.
-    pub stderr: ChildStderr,
-    /// The command being executed.
-    pub command: exe::Command,
+  /// Convert the return value of a libc call into an [`io::Result`].
+  fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+      Err(io::Error::last_os_error())
+    } else {
+      Ok(ret)
+    }
   }
 
-  impl Streaming {
-    /// Stream the output of this process through `act`, then analyze the exit status.
-    pub async fn exhaust_byte_streams_and_wait<F, A>(
-      self,
-      act: A,
-    ) -> Result<(), exe::CommandErrorWrapper>
-    where
-      F: Future<Output=Result<(), exe::CommandError>>,
-      A: Fn(StdioChunk) -> F,
-    {
-      let Self {
-        mut stdout,
-        mut stderr,
-        mut child,
-        command,
-      } = self;
+  /// The dimensions of a terminal, in characters.
+  #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+  pub struct PtySize {
+    /// Number of lines.
+    pub rows: u16,
+    /// Number of columns.
+    pub cols: u16,
+  }
 
-      let status = async move {
-        let mut out_buf = [0u8; 300];
-        let mut err_buf = [0u8; 300];
-        /* TODO: find a nicer way to handle this loop! */
-        let mut out_done: bool = false;
-        let mut err_done: bool = false;
-        loop {
-          if out_done {
-            if err_done {
-              break;
-            } else {
-              let num_read = stderr.read(&mut err_buf).await?;
-              if num_read == 0 {
-                err_done = true;
-              } else {
-                let chunk = StdioChunk::Err(err_buf[..num_read].to_vec());
-                act(chunk).await?;
-              }
-            }
-          } else {
-            if err_done {
-              let num_read = stdout.read(&mut out_buf).await?;
-              if num_read == 0 {
-                out_done = true;
-              } else {
-                let chunk = StdioChunk::Out(out_buf[..num_read].to_vec());
-                act(chunk).await?;
-              }
-            } else {
-              tokio::select! {
-                Ok(num_read) = stdout.read(&mut out_buf) => {
-                  if num_read == 0 {
-                    out_done = true;
-                  } else {
-                    let chunk = StdioChunk::Out(out_buf[..num_read].to_vec());
-                    act(chunk).await?;
-                  }
-                }
-                Ok(num_read) = stderr.read(&mut err_buf) => {
-                  if num_read == 0 {
-                    err_done = true;
-                  } else {
-                    let chunk = StdioChunk::Err(err_buf[..num_read].to_vec());
-                    act(chunk).await?;
-                  }
-                }
-                else => { break; }
-              }
-            }
-          }
-        }
-        let output = child.status().await?;
-        Ok(output)
+  impl Default for PtySize {
+    fn default() -> Self { Self { rows: 24, cols: 80 } }
+  }
+
+  impl PtySize {
+    fn winsize(self) -> libc::winsize {
+      let Self { rows, cols } = self;
+      libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
       }
-      .await
-      .map_err(|e: exe::CommandError| {
-        e.command_with_context(command.clone(), "merging async streams".to_string())
-      })?;
+    }
 
-      exe::CommandError::analyze_exit_status(status)
-        .map_err(|e| e.command_with_context(command, "checking async exit status".to_string()))?;
+    /// Set the size of the terminal `fd`, which sends `SIGWINCH` to its foreground process group.
+    fn apply(self, fd: RawFd) -> io::Result<()> {
+      let winsize = self.winsize();
+      check(unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &winsize) })?;
       Ok(())
     }
+  }
 
-    /// Stream the output of this process through `act`, then analyze the exit status.
-    pub async fn exhaust_string_streams_and_wait<F, A>(
-      self,
-      act: A,
-    ) -> Result<(), exe::CommandErrorWrapper>
-    where
-      F: Future<Output=Result<(), exe::CommandError>>,
-      A: Fn(StdioLine) -> F,
-    {
-      let Self {
-        stdout,
-        stderr,
-        mut child,
-        command,
-      } = self;
-      /* stdout wrapping. */
-      let mut out_lines = BufReader::new(stdout).lines();
-      /* stderr wrapping. */
-      let mut err_lines = BufReader::new(stderr).lines();
-
-      /* Crossing the streams!!! */
-      let status = async move {
-        loop {
-          let line = tokio::select! {
-            Some(err) = err_lines.next() => StdioLine::Err(err?),
-            Some(out) = out_lines.next() => StdioLine::Out(out?),
-            else => break,
-          };
-          act(line).await?;
-        }
-        let output = child.status().await?;
-        Ok(output)
+  /// Configuration for [`PtyStreamable::invoke_pty`].
+  #[derive(Debug, Copy, Clone, Default)]
+  pub struct PtyOptions {
+    /// The initial size of the terminal.
+    pub size: PtySize,
+    /// Whether to put the terminal in raw mode before starting the child, which disables echo,
+    /// line buffering, and the translation of control characters into signals.
+    pub raw: bool,
+  }
+
+  /// Put the terminal `fd` into raw mode, returning its previous settings.
+  fn make_raw(fd: RawFd) -> io::Result<libc::termios> {
+    let mut termios: libc::termios = unsafe { mem::zeroed() };
+    check(unsafe { libc::tcgetattr(fd, &mut termios) })?;
+    let original = termios;
+    unsafe { libc::cfmakeraw(&mut termios) };
+    check(unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) })?;
+    Ok(original)
+  }
+
+  /// Keeps a terminal in raw mode, restoring its original settings when dropped.
+  ///
+  /// This is useful to pass keystrokes from the parent's own terminal through to a child
+  /// process unmodified.
+  #[derive(Debug)]
+  pub struct RawModeGuard {
+    fd: RawFd,
+    original: libc::termios,
+  }
+
+  impl RawModeGuard {
+    /// Put the terminal `fd` into raw mode.
+    ///
+    /// `fd` must remain open for the lifetime of the guard.
+    pub fn new(fd: RawFd) -> io::Result<Self> {
+      let original = make_raw(fd)?;
+      Ok(Self { fd, original })
+    }
+  }
+
+  impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+      unsafe {
+        libc::tcsetattr(self.fd, libc::TCSANOW, &self.original);
       }
-      .await
-      .map_err(|e: exe::CommandError| {
-        e.command_with_context(command.clone(), "merging async streams".to_string())
-      })?;
+    }
+  }
 
-      exe::CommandError::analyze_exit_status(status)
-        .map_err(|e| e.command_with_context(command, "checking async exit status".to_string()))?;
-      Ok(())
+  /// Set close-on-exec for `fd`, so it isn't leaked into the child.
+  fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    let flags = check(unsafe { libc::fcntl(fd, libc::F_GETFD) })?;
+    check(unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) })?;
+    Ok(())
+  }
+
+  /// Allocate a new pty, returning the (master, slave) pair.
+  fn open_pty(options: PtyOptions) -> io::Result<(File, File)> {
+    let mut master: RawFd = -1;
+    let mut slave: RawFd = -1;
+    let winsize = options.size.winsize();
+    check(unsafe {
+      libc::openpty(
+        &mut master,
+        &mut slave,
+        ptr::null_mut(),
+        ptr::null(),
+        &winsize,
+      )
+    })?;
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    set_cloexec(master.as_raw_fd())?;
+    set_cloexec(slave.as_raw_fd())?;
+    if options.raw {
+      make_raw(slave.as_raw_fd())?;
     }
+    Ok((master, slave))
+  }
 
-    /// Copy over all stderr lines to our stderr, and stdout lines to our stdout.
-    async fn stdio_streams_callback(line: StdioLine) -> Result<(), exe::CommandError> {
-      match line {
-        StdioLine::Err(err) => {
-          let err = str::from_utf8(err.as_bytes()).expect("UTF8 DECODING STDERR FAILED");
-          eprintln!("{}", err);
-        },
-        StdioLine::Out(out) => {
-          let out = str::from_utf8(out.as_bytes()).expect("UTF8 DECODING STDOUT FAILED");
-          println!("{}", out);
-        },
+  /// Reads output from the master side of a pty.
+  ///
+  /// Linux reports `EIO` instead of EOF once every handle to the slave side has been closed, so
+  /// we translate that into EOF.
+  struct PtyReader(Async<File>);
+
+  impl AsyncRead for PtyReader {
+    fn poll_read(
+      mut self: Pin<&mut Self>,
+      cx: &mut Context<'_>,
+      buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+      match Pin::new(&mut self.0).poll_read(cx, buf) {
+        Poll::Ready(Err(e)) if e.raw_os_error() == Some(libc::EIO) => Poll::Ready(Ok(0)),
+        poll => poll,
       }
-      Ok(())
     }
+  }
 
-    /// Wait for the process to exit, printing lines of stdout and stderr to the terminal.
-    pub async fn wait(self) -> Result<(), exe::CommandErrorWrapper> {
-      self
-        .exhaust_string_streams_and_wait(Self::stdio_streams_callback)
-        .await?;
-      Ok(())
+  /// A handle to write input to and resize the terminal of a child process.
+  pub struct PtyInput(Async<File>);
+
+  impl PtyInput {
+    /// Write all of `input` to the terminal, as if it were typed.
+    pub async fn send(&self, input: &[u8]) -> io::Result<()> {
+      let mut master = &self.0;
+      master.write_all(input).await?;
+      master.flush().await
     }
+
+    /// Change the size of the terminal, which notifies the child with `SIGWINCH`.
+    pub fn resize(&self, size: PtySize) -> io::Result<()> { size.apply(self.0.as_raw_fd()) }
   }
 
-  /// A chunk of either stdout or stderr from a subprocess.
-  #[derive(Debug, Clone, PartialEq, Eq)]
-  pub enum StdioChunk {
-    /// A chunk of stdout.
-    Out(Vec<u8>),
-    /// A chunk of stderr.
-    Err(Vec<u8>),
+  /// A handle to the result of invoking a process attached to a pty.
+  pub struct PtyStreaming {
+    /// The handle to the live child process.
+    pub child: exe::ManagedChild,
+    /// The handle to write to the terminal.
+    pub input: Option<PtyInput>,
+    output: PtyReader,
+    /// The command being executed.
+    pub command: exe::Command,
   }
 
-  /// A line of either stdout or stderr from a subprocess.
-  #[derive(Debug, Clone, PartialEq, Eq)]
-  pub enum StdioLine {
-    /// A line of stdout.
-    Out(String),
-    /// A line of stderr.
-    Err(String),
+  impl PtyStreaming {
+    /// Stream the merged output of this process as [`StdioChunk::Out`]s, followed by its exit
+    /// status. See [`Streaming::into_chunks`].
+    pub fn into_chunks(
+      self,
+    ) -> impl Stream<Item=Result<StdioEvent<StdioChunk>, exe::CommandErrorWrapper>> {
+      let Self {
+        child,
+        input,
+        output,
+        command,
+      } = self;
+      /* Keep the terminal open until all output has been read. */
+      let output = stream::read_chunks(output, StdioChunk::Out).map(move |chunk| {
+        let _ = &input;
+        chunk
+      });
+      let stage = pipe::Stage {
+        command: Arc::new(command),
+        child,
+      };
+      Streaming::into_events(vec![stage], None, output)
+    }
   }
 
-  /// Trait that defines "asynchronously" invokable processes.
-  pub trait Streamable {
-    /// Invoke a child process and return a handle to its output streams.
-    fn invoke_streaming(self) -> Result<Streaming, exe::CommandErrorWrapper>;
+  /// Trait that defines processes which can be invoked attached to a pty.
+  pub trait PtyStreamable {
+    /// Invoke a child process as the leader of a new session, with a new pty as its
+    /// controlling terminal and as its stdin, stdout, and stderr.
+    ///
+    /// Since the child leads its own session, [`exe::Command::process_group`] is ignored. The
+    /// terminal is the child's stdin, so this fails if [`exe::Command::stdin`] is set.
+    fn invoke_pty(self, options: PtyOptions) -> Result<PtyStreaming, exe::CommandErrorWrapper>;
   }
 
-  impl Streamable for exe::Command {
-    fn invoke_streaming(self) -> Result<Streaming, exe::CommandErrorWrapper> {
-      let mut command = self.clone().command();
-      let mut child = command
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
+  impl PtyStreamable for exe::Command {
+    fn invoke_pty(self, options: PtyOptions) -> Result<PtyStreaming, exe::CommandErrorWrapper> {
+      if self.stdin.is_some() {
+        let error: exe::CommandError = io::Error::new(
+          io::ErrorKind::InvalidInput,
+          "stdin cannot be configured for a pty process, which reads from the terminal",
+        )
+        .into();
+        return Err(hook::failed(
+          error.command_with_context(self, "spawning pty process".to_string()),
+        ));
+      }
+      let config = Arc::new(exe::Command {
+        process_group: exe::ProcessGroup::NewSession,
+        ..self.clone()
+      });
+
+      let mut command = config.command().map_err(hook::failed)?;
+      let spawned = (|| {
+        let (master, slave) = open_pty(options)?;
+        command
+          .stdin(Stdio::from(slave.try_clone()?))
+          .stdout(Stdio::from(slave.try_clone()?))
+          .stderr(Stdio::from(slave));
+        /* This runs after the session is created and stdio is redirected, so the terminal is
+         * now open as fd 0. */
+        unsafe {
+          command.pre_exec(|| {
+            check(libc::ioctl(0, libc::TIOCSCTTY, 0))?;
+            Ok(())
+          });
+        }
+        let child = exe::ManagedChild::spawn(&mut command, config)?;
+        io::Result::Ok((child, master))
+      })();
+      /* Close our handles to the slave side, so we see EOF when the child exits. */
+      drop(command);
+      let (child, master) = spawned
         .map_err(|e| e.into())
         .map_err(|e: exe::CommandError| {
-          e.command_with_context(self.clone(), "spawning async process".to_string())
-        })?;
-      let stdout = child.stdout.take().unwrap();
-      let stderr = child.stderr.take().unwrap();
-      Ok(Streaming {
+          e.command_with_context(self.clone(), "spawning pty process".to_string())
+        })
+        .map_err(hook::failed)?;
+
+      let (input, output) = (|| {
+        let input = Async::new(master.try_clone()?)?;
+        let output = Async::new(master)?;
+        io::Result::Ok((input, output))
+      })()
+      .map_err(|e| e.into())
+      .map_err(|e: exe::CommandError| {
+        e.command_with_context(self.clone(), "opening pty master".to_string())
+      })
+      .map_err(hook::failed)?;
+
+      Ok(PtyStreaming {
         child,
-        stdout,
-        stderr,
+        input: Some(PtyInput(input)),
+        output: PtyReader(output),
         command: self,
       })
     }
@@ -752,20 +4970,28 @@ pub mod stream {
 pub mod sh {
   use super::{
     base::{self, CommandBase},
-    exe, fs,
+    exe,
+    fs::{self, PathWrapper},
     sync::SyncInvocable,
   };
 
   use async_trait::async_trait;
   use displaydoc::Display;
   use indexmap::IndexMap;
+  use sha2::{Digest, Sha256};
   use tempfile::{NamedTempFile, TempPath};
   use thiserror::Error;
 
   use std::{
-    ffi::OsString,
-    io::{self, BufRead, Write},
+    env,
+    ffi::{OsStr, OsString},
+    fs as std_fs,
+    io::{self, Write},
+    mem,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::PathBuf,
     str,
+    sync::Arc,
   };
 
   /// Errors that may occur when executing a shell script.
@@ -779,6 +5005,10 @@ pub mod sh {
     Io(#[from] io::Error),
     /// utf-8 decoding error {0}
     Utf8(#[from] str::Utf8Error),
+    /// malformed environment entry {0:?}
+    EnvEntry(OsString),
+    /// output of env script was missing a section
+    MissingSection,
   }
 
   impl ShellError {
@@ -801,9 +5031,84 @@ pub mod sh {
     pub error: ShellError,
   }
 
+  /// A shell which executes [`ShellScript`]s, along with any flags to pass before the script.
+  ///
+  /// This defaults to `sh` without any flags. The interpreter executable is searched for in
+  /// `$PATH` if it does not contain a slash.
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use super_process::{sh, exe, base::CommandBase, sync::SyncInvocable};
+  ///
+  /// let source = sh::ShellSource {
+  ///   contents: b"[[ -n bash ]] || exit 2; false; exit 3".to_vec(),
+  /// };
+  /// let mut script = source.into_script().await.unwrap();
+  /// script.interpreter = sh::Interpreter::bash().strict();
+  /// let command = script.with_command(exe::Command::default())
+  ///   .setup_command().await.unwrap();
+  ///
+  /// match command.invoke().await {
+  ///   Err(exe::CommandErrorWrapper {
+  ///     error: exe::CommandError::NonZeroExit(1),
+  ///     ..
+  ///   }) => (),
+  ///   _ => unreachable!(),
+  /// }
+  /// # }) // async
+  ///```
+  #[derive(Debug, Clone)]
+  pub struct Interpreter {
+    /// The shell executable.
+    pub exe: exe::Exe,
+    /// Flags to pass to the shell before the script path, such as `-x` or `--norc`.
+    pub flags: exe::Argv,
+  }
+
+  impl Default for Interpreter {
+    fn default() -> Self { Self::named("sh") }
+  }
+
+  impl Interpreter {
+    /// Use the shell `name`, which may be a path or a name to search for in `$PATH`.
+    pub fn named(name: impl AsRef<OsStr>) -> Self {
+      Self {
+        exe: exe::Exe::from(&name),
+        flags: exe::Argv::default(),
+      }
+    }
+
+    /// The POSIX shell `sh`.
+    pub fn sh() -> Self { Self::named("sh") }
+
+    /// The `bash` shell.
+    pub fn bash() -> Self { Self::named("bash") }
+
+    /// The `zsh` shell.
+    pub fn zsh() -> Self { Self::named("zsh") }
+
+    /// The `dash` shell.
+    pub fn dash() -> Self { Self::named("dash") }
+
+    /// Append `flags` to the flags passed to the shell.
+    pub fn with_flags<R: AsRef<OsStr>, I: IntoIterator<Item=R>>(mut self, flags: I) -> Self {
+      self
+        .flags
+        .0
+        .extend(flags.into_iter().map(|flag| flag.as_ref().to_os_string()));
+      self
+    }
+
+    /// Exit on the first failing command or unset variable, and fail a pipeline if any stage
+    /// fails, with `-e -u -o pipefail`.
+    ///
+    /// `pipefail` is supported by `bash` and `zsh`, but not by every `sh`.
+    pub fn strict(self) -> Self { self.with_flags(["-e", "-u", "-o", "pipefail"]) }
+  }
+
   /// Generate a shell script to execute via [`ShellScript`].
   ///
-  /// This script is generated by writing [`Self::contents`] to a temporary file.
+  /// This script is generated by writing [`Self::contents`] to a temporary file, which is deleted
+  /// once the script and every command executing it have been dropped.
   ///```
   /// # tokio_test::block_on(async {
   /// use super_process::{sh, exe, base::CommandBase, sync::SyncInvocable};
@@ -811,20 +5116,67 @@ pub mod sh {
   /// let contents = "echo hey".as_bytes().to_vec();
   /// let source = sh::ShellSource { contents };
   /// let script = source.into_script().await.expect("generating shell script failed");
+  /// let script_path = script.script_path.clone().0.0;
   /// let command = script.with_command(exe::Command::default())
   ///   .setup_command().await.unwrap();
   ///
   /// let output = command.invoke().await.expect("shell script should succeed");
   /// assert!(b"hey\n".as_ref() == &output.stdout);
+  /// assert!(!script_path.exists());
   /// # }) // async
   ///```
-  #[derive(Debug, Clone)]
+  #[derive(Debug, Clone, Default)]
   pub struct ShellSource {
     /// The bytes of a shell script to be written to file.
     pub contents: Vec<u8>,
   }
 
   impl ShellSource {
+    /// The longest single argument accepted by `execve()` on Linux, including the trailing NUL.
+    const MAX_ARGUMENT_LENGTH: usize = 128 * 1024;
+
+    /// Space to leave below `ARG_MAX` for anything else `execve()` places on the stack, as
+    /// `xargs` does.
+    const ARG_MAX_HEADROOM: usize = 2048;
+
+    fn fits_in_argument(&self) -> bool {
+      self.contents.len() < Self::MAX_ARGUMENT_LENGTH && !self.contents.contains(&0)
+    }
+
+    /// Whether `execve()` accepts `base` with `interpreter -c <script>` prepended, given that the
+    /// arguments and environment must fit within `ARG_MAX` together.
+    fn fits_in_command(&self, interpreter: &Interpreter, base: &exe::Command) -> bool {
+      if !self.fits_in_argument() {
+        return false;
+      }
+      let arg_max = match unsafe { libc::sysconf(libc::_SC_ARG_MAX) } {
+        n if n > 0 => n as usize,
+        _ => return false,
+      };
+      /* Each string is followed by a NUL, and referenced by a pointer in argv or envp. */
+      let entry = |len: usize| len + 1 + mem::size_of::<*const libc::c_char>();
+      let exe::Exe(fs::File(interpreter_exe)) = &interpreter.exe;
+      let exe::Exe(fs::File(base_exe)) = &base.exe;
+      let args: usize = [
+        interpreter_exe.as_os_str(),
+        OsStr::new("-c"),
+        interpreter_exe.as_os_str(),
+        base_exe.as_os_str(),
+      ]
+      .into_iter()
+      .chain(interpreter.flags.0.iter().map(|arg| arg.as_os_str()))
+      .chain(base.argv.0.iter().map(|arg| arg.as_os_str()))
+      .map(|arg| entry(arg.len()))
+      .sum();
+      let env: usize = base
+        .env
+        .resolve(env::vars_os())
+        .iter()
+        .map(|(k, v)| entry(k.len() + 1 + v.len()))
+        .sum();
+      args + env + entry(self.contents.len()) + Self::ARG_MAX_HEADROOM <= arg_max
+    }
+
     fn write_to_temp_path(self) -> io::Result<TempPath> {
       /* Create the script. */
       let (mut script_file, script_path) = NamedTempFile::new()?.into_parts();
@@ -837,59 +5189,123 @@ pub mod sh {
 
     /// Create a handle to a shell script backed by a temp file.
     ///
-    /// *FIXME: we don't ever delete the temp file! Use lifetimes to avoid this!*
-    pub async fn into_script(self) -> Result<ShellScript, ShellError> {
-      let script_path = self.write_to_temp_path()?;
-
-      /* FIXME: We don't ever delete the script! */
-      let script_path = exe::Exe(fs::File(
-        script_path
-          .keep()
-          .expect("should never be any error keeping the shell script path"),
-      ));
+    /// The temp file is deleted when the returned script is dropped, or after every command it
+    /// was provided to has been dropped. Use [`ShellScript::keep`] to retain it.
+    pub async fn into_script(self) -> Result<ShellScript, ShellError> { Ok(self.write_script()?) }
 
-      Ok(ShellScript { script_path })
+    fn write_script(self) -> io::Result<ShellScript> {
+      let temp_file = self.write_to_temp_path()?;
+      let script_path = exe::Exe(fs::File(temp_file.to_path_buf()));
+      Ok(ShellScript {
+        script_path,
+        temp_file: Some(Arc::new(temp_file)),
+        interpreter: Interpreter::default(),
+      })
     }
   }
 
   /// Request for dumping the components of the environment after evaluating a shell script.
   ///```
   /// # tokio_test::block_on(async {
-  /// use std::ffi::OsStr;
-  /// use super_process::{sh, exe};
+  /// use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+  /// use super_process::sh;
   ///
   /// let env = sh::EnvAfterScript {
   ///   source: sh::ShellSource {
   ///     contents: b"export A=3".to_vec(),
   ///   },
+  ///   ..Default::default()
   /// };
-  /// let exe::EnvModifications(env) = env.extract_env_bindings().await.unwrap();
+  /// let env = env.extract_env_bindings().await.unwrap().bindings();
   /// let env_val = env.get(OsStr::new("A")).unwrap().to_str().unwrap();
   /// assert_eq!(3, env_val.parse::<usize>().unwrap());
+  ///
+  /// // Values may span multiple lines and need not be valid UTF-8.
+  /// let env = sh::EnvAfterScript {
+  ///   source: sh::ShellSource {
+  ///     contents: b"export B=\"$(printf 'x\\ny=z\\n\\377')\"".to_vec(),
+  ///   },
+  ///   ..Default::default()
+  /// };
+  /// let env = env.extract_env_bindings().await.unwrap().bindings();
+  /// let env_val = env.get(OsStr::new("B")).unwrap().as_bytes();
+  /// assert_eq!(b"x\ny=z\n\xFF".as_ref(), env_val);
+  /// assert!(!env.contains_key(OsStr::new("y")));
   /// # }) // async
   ///```
-  #[derive(Debug, Clone)]
+  #[derive(Debug, Clone, Default)]
   pub struct EnvAfterScript {
     /// Script to run before extracting the environment.
     pub source: ShellSource,
+    /// The shell to execute the script with.
+    pub interpreter: Interpreter,
+    /// Where to cache the result of the script, if anywhere.
+    pub cache: Option<EnvCache>,
+  }
+
+  /// What to print to stdout after running an [`EnvAfterScript`].
+  #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+  enum Capture {
+    /// The environment after the script.
+    Env,
+    /// The environment before and after the script.
+    EnvDiff,
+    /// The environment before and after the script, then function and alias definitions.
+    ShellState,
   }
 
   impl EnvAfterScript {
-    fn into_source(self) -> ShellSource {
+    /// Wrap the script so that the only output on stdout is from `env -0` and the other commands
+    /// requested by `capture`.
+    ///
+    /// Sections of the output are separated by a NUL byte. Since every entry from `env -0` is
+    /// terminated by NUL, the end of an env section is marked by an empty entry.
+    fn into_source(self, capture: Capture) -> ShellSource {
       let Self {
-        source: ShellSource { mut contents },
+        source: ShellSource { contents },
+        interpreter: _,
+        cache: _,
       } = self;
-      contents.extend_from_slice(b"\n\nexec env");
-      ShellSource { contents }
+      let mut wrapped: Vec<u8> = Vec::new();
+      if capture != Capture::Env {
+        wrapped.extend_from_slice(b"env -0\nprintf '\\0'\n");
+      }
+      /* Send anything the script writes to stdout to stderr instead. */
+      wrapped.extend_from_slice(b"exec 9>&1 1>&2\n\n");
+      wrapped.extend_from_slice(&contents);
+      wrapped.extend_from_slice(b"\n\nexec 1>&9 9>&-\n");
+      if capture == Capture::ShellState {
+        wrapped.extend_from_slice(
+          br#"env -0
+printf '\0'
+if [ -n "${BASH_VERSION-}" ]; then
+  declare -f
+  printf '\0'
+  alias -p
+elif [ -n "${ZSH_VERSION-}" ]; then
+  typeset -f
+  printf '\0'
+  alias -L
+else
+  echo "capturing shell functions requires bash or zsh" >&2
+  exit 2
+fi"#,
+        );
+      } else {
+        wrapped.extend_from_slice(b"exec env -0");
+      }
+      ShellSource { contents: wrapped }
     }
 
-    async fn into_command(self) -> Result<exe::Command, ShellErrorWrapper> {
+    async fn into_command(self, capture: Capture) -> Result<exe::Command, ShellErrorWrapper> {
+      let interpreter = self.interpreter.clone();
       /* Write script file. */
-      let source = self.into_source();
-      let script = source
+      let source = self.into_source(capture);
+      let mut script = source
         .into_script()
         .await
         .map_err(|e| e.with_context("when writing env script to file".to_string()))?;
+      script.interpreter = interpreter;
       /* Generate command. */
       let sh = script.with_command(exe::Command::default());
       let command = sh
@@ -905,9 +5321,36 @@ pub mod sh {
       Ok(command)
     }
 
-    async fn extract_stdout(self) -> Result<Vec<u8>, ShellErrorWrapper> {
+    /// Execute the script for `capture`, or read its output from the cache, and `parse` it.
+    async fn extract<T>(
+      self,
+      capture: Capture,
+      parse: impl Fn(&[u8]) -> Result<T, ShellErrorWrapper>,
+    ) -> Result<T, ShellErrorWrapper> {
+      /* Check the cache. */
+      let cache_key = self
+        .cache
+        .as_ref()
+        .map(|cache| cache.key(&self, capture).map(|key| (cache.clone(), key)))
+        .transpose()
+        .map_err(|e| ShellError::from(e).with_context("when computing env cache key".to_string()))?;
+      if let Some((ref cache, ref key)) = cache_key {
+        let cached = cache
+          .load(key)
+          .map_err(|e| ShellError::from(e).with_context("when reading env cache".to_string()))?;
+        if let Some(stdout) = cached {
+          /* An entry which fails to parse is treated as a miss, and replaced below. */
+          match parse(&stdout) {
+            Ok(result) => return Ok(result),
+            Err(_) => cache.remove(key).map_err(|e| {
+              ShellError::from(e).with_context("when removing invalid env cache entry".to_string())
+            })?,
+          }
+        }
+      }
+
       /* Setup command. */
-      let command = self.into_command().await?;
+      let command = self.into_command(capture).await?;
 
       /* Execute command. */
       let output = command
@@ -915,35 +5358,414 @@ pub mod sh {
         .await
         .map_err(|e| e.into())
         .map_err(|e: ShellError| e.with_context("when extracting env bindings".to_string()))?;
+      let result = parse(&output.stdout)?;
 
-      Ok(output.stdout)
+      if let Some((cache, key)) = cache_key {
+        cache
+          .store(&key, &output.stdout)
+          .map_err(|e| ShellError::from(e).with_context("when writing env cache".to_string()))?;
+      }
+      Ok(result)
     }
 
     /// Execute the wrapped script and parse the output of the `env` command executed afterwards!
+    ///
+    /// Entries are separated by NUL bytes, so values may contain newlines or arbitrary bytes.
     pub async fn extract_env_bindings(self) -> Result<exe::EnvModifications, ShellErrorWrapper> {
-      let stdout = self.extract_stdout().await?;
+      let env_map = self
+        .extract(Capture::Env, |stdout| {
+          parse_env(stdout).map_err(|e| e.with_context("when parsing env bindings".to_string()))
+        })
+        .await?;
+      Ok(env_map.into())
+    }
 
-      /* Parse output into key-value pairs. */
-      let mut env_map: IndexMap<OsString, OsString> = IndexMap::new();
-      for line in stdout.lines() {
-        let line = line
-          .map_err(|e| e.into())
-          .map_err(|e: ShellError| e.with_context("when extracting stdout line".to_string()))?;
-        /* FIXME: we currently just ignore lines that don't have an '=' -- fix this! */
-        if let Some(equals_index) = line.find('=') {
-          let key = &line[..equals_index];
-          let value = &line[equals_index + 1..];
-          env_map.insert(key.into(), value.into());
+    /// Execute the wrapped script and compare the environment before and after it ran.
+    ///
+    /// Both snapshots are taken within the same shell process, so variables set by the shell
+    /// itself are not reported. The diff converts into [`exe::EnvModifications`], to replay the
+    /// changes onto other commands:
+    ///```
+    /// # tokio_test::block_on(async {
+    /// use std::{env, ffi::OsString, path::PathBuf};
+    /// use super_process::{sh, exe, fs, sync::SyncInvocable};
+    ///
+    /// env::set_var("SUPER_PROCESS_DIFF_UNSET", "1");
+    /// env::set_var("SUPER_PROCESS_DIFF_CHANGED", "1");
+    /// let env = sh::EnvAfterScript {
+    ///   source: sh::ShellSource {
+    ///     contents: br#"
+    ///       echo "this is not part of the environment"
+    ///       export SUPER_PROCESS_DIFF_ADDED=1
+    ///       export SUPER_PROCESS_DIFF_CHANGED=2
+    ///       unset SUPER_PROCESS_DIFF_UNSET
+    ///     "#.to_vec(),
+    ///   },
+    ///   ..Default::default()
+    /// };
+    /// let diff = env.extract_env_diff().await.unwrap();
+    /// assert_eq!(
+    ///   vec![(&OsString::from("SUPER_PROCESS_DIFF_ADDED"), &OsString::from("1"))],
+    ///   diff.added.iter().collect::<Vec<_>>(),
+    /// );
+    /// assert_eq!(
+    ///   vec![(&OsString::from("SUPER_PROCESS_DIFF_CHANGED"), &OsString::from("2"))],
+    ///   diff.changed.iter().collect::<Vec<_>>(),
+    /// );
+    /// assert_eq!(vec![OsString::from("SUPER_PROCESS_DIFF_UNSET")], diff.unset);
+    ///
+    /// let command = exe::Command {
+    ///   exe: exe::Exe(fs::File(PathBuf::from("sh"))),
+    ///   argv: [
+    ///     "-c",
+    ///     "echo $SUPER_PROCESS_DIFF_ADDED $SUPER_PROCESS_DIFF_CHANGED \
+    ///      ${SUPER_PROCESS_DIFF_UNSET-unset}",
+    ///   ].as_ref().into(),
+    ///   env: diff.into(),
+    ///   ..Default::default()
+    /// };
+    /// let output = command.invoke().await.unwrap();
+    /// assert_eq!(b"1 2 unset\n".as_ref(), &output.stdout);
+    /// # }) // async
+    ///```
+    pub async fn extract_env_diff(self) -> Result<exe::EnvDiff, ShellErrorWrapper> {
+      let (before, after) = self
+        .extract(Capture::EnvDiff, |stdout| {
+          parse_env_snapshots(stdout)
+            .map_err(|e| e.with_context("when parsing env snapshots".to_string()))
+        })
+        .await?;
+      Ok(exe::EnvDiff::between(&before, &after))
+    }
+
+    /// Execute the wrapped script and capture the changes to its environment, along with every
+    /// shell function and alias it defined.
+    ///
+    /// This requires [`Self::interpreter`] to be `bash` or `zsh`.
+    pub async fn extract_shell_state(self) -> Result<ShellState, ShellErrorWrapper> {
+      self
+        .extract(Capture::ShellState, |stdout| {
+          parse_shell_state(stdout)
+            .map_err(|e| e.with_context("when parsing shell state".to_string()))
+        })
+        .await
+    }
+  }
+
+  /// An on-disk cache for the results of [`EnvAfterScript`]s.
+  ///
+  /// Entries are keyed by a hash of the script contents, the interpreter, the current working
+  /// directory, and the environment variables listed in [`Self::key_vars`]. Each entry is written
+  /// to a temp file and atomically renamed into place, so multiple processes may share a cache
+  /// directory. An entry which cannot be parsed is discarded, and the script is executed again.
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::{ffi::OsStr, fs};
+  /// use super_process::sh;
+  ///
+  /// let dir = tempfile::tempdir().unwrap();
+  /// let runs = dir.path().join("runs");
+  /// let cache = sh::EnvCache::new(dir.path().join("cache"));
+  /// let env = sh::EnvAfterScript {
+  ///   source: sh::ShellSource {
+  ///     contents: format!("echo run >> '{}'; export A=1", runs.display()).into_bytes(),
+  ///   },
+  ///   cache: Some(cache.clone()),
+  ///   ..Default::default()
+  /// };
+  ///
+  /// for _ in 0..3 {
+  ///   let bindings = env.clone().extract_env_bindings().await.unwrap().bindings();
+  ///   assert_eq!(OsStr::new("1"), bindings.get(OsStr::new("A")).unwrap());
+  /// }
+  /// assert_eq!("run\n", fs::read_to_string(&runs).unwrap());
+  ///
+  /// // The script runs again after its entry is invalidated.
+  /// cache.invalidate(&env).unwrap();
+  /// env.clone().extract_env_bindings().await.unwrap();
+  /// assert_eq!("run\nrun\n", fs::read_to_string(&runs).unwrap());
+  ///
+  /// // An entry which cannot be parsed is replaced by running the script again.
+  /// for entry in fs::read_dir(&cache.dir).unwrap() {
+  ///   fs::write(entry.unwrap().path(), b"garbage").unwrap();
+  /// }
+  /// let bindings = env.clone().extract_env_bindings().await.unwrap().bindings();
+  /// assert_eq!(OsStr::new("1"), bindings.get(OsStr::new("A")).unwrap());
+  /// assert_eq!("run\nrun\nrun\n", fs::read_to_string(&runs).unwrap());
+  ///
+  /// // Clearing the cache also removes temp files left behind by interrupted writes.
+  /// fs::write(cache.dir.join(".tmpAbC123"), b"partial").unwrap();
+  /// cache.clear().unwrap();
+  /// assert_eq!(0, fs::read_dir(&cache.dir).unwrap().count());
+  /// # }) // async
+  ///```
+  #[derive(Debug, Clone)]
+  pub struct EnvCache {
+    /// The directory holding cache entries, which is created if it does not exist.
+    pub dir: PathBuf,
+    /// The variables from this process's environment which may affect the result of a script.
+    /// If this is `None`, the entire environment is part of the key.
+    pub key_vars: Option<Vec<OsString>>,
+  }
+
+  impl EnvCache {
+    const EXTENSION: &'static str = "env";
+    /// The prefix of temp files which have not yet been renamed into place.
+    const TEMP_PREFIX: &'static str = ".tmp";
+
+    /// Cache entries in `dir`, keyed by the entire environment.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+      Self {
+        dir: dir.into(),
+        key_vars: None,
+      }
+    }
+
+    fn key(&self, script: &EnvAfterScript, capture: Capture) -> io::Result<String> {
+      let mut hasher = Sha256::new();
+      /* Prefix every field with its length, so that no two sets of inputs hash the same bytes. */
+      let mut field = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+      };
+      field(format!("{:?}", capture).as_bytes());
+      /* Scripts often source files relative to the working directory. */
+      field(env::current_dir()?.as_os_str().as_bytes());
+      field(&script.source.contents);
+      let Interpreter { exe, flags } = &script.interpreter;
+      field(exe.clone().into_path_buf().as_os_str().as_bytes());
+      field(&(flags.0.len() as u64).to_le_bytes());
+      for flag in flags.0.iter() {
+        field(flag.as_bytes());
+      }
+      let vars: Vec<(OsString, Option<OsString>)> = match self.key_vars {
+        Some(ref names) => names
+          .iter()
+          .map(|name| (name.clone(), env::var_os(name)))
+          .collect(),
+        None => {
+          let mut vars: Vec<_> = env::vars_os().map(|(k, v)| (k, Some(v))).collect();
+          vars.sort();
+          vars
+        },
+      };
+      for (name, value) in vars.iter() {
+        field(name.as_bytes());
+        match value {
+          Some(value) => {
+            field(b"set");
+            field(value.as_bytes());
+          },
+          None => field(b"unset"),
         }
       }
+      Ok(
+        hasher
+          .finalize()
+          .iter()
+          .map(|b| format!("{:02x}", b))
+          .collect(),
+      )
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+      self.dir.join(format!("{}.{}", key, Self::EXTENSION))
+    }
+
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+      match std_fs::read(self.entry_path(key)) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+      }
+    }
+
+    fn store(&self, key: &str, contents: &[u8]) -> io::Result<()> {
+      std_fs::create_dir_all(&self.dir)?;
+      /* Write to a temp file in the same directory, so it can be atomically renamed. */
+      let mut entry = tempfile::Builder::new()
+        .prefix(Self::TEMP_PREFIX)
+        .tempfile_in(&self.dir)?;
+      entry.write_all(contents)?;
+      entry.as_file().sync_all()?;
+      entry.persist(self.entry_path(key))?;
+      Ok(())
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+      match std_fs::remove_file(self.entry_path(key)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+      }
+    }
+
+    /// Remove any cached results for `script` in the current environment.
+    pub fn invalidate(&self, script: &EnvAfterScript) -> io::Result<()> {
+      for capture in [Capture::Env, Capture::EnvDiff, Capture::ShellState] {
+        self.remove(&self.key(script, capture)?)?;
+      }
+      Ok(())
+    }
+
+    /// Remove every entry from the cache, along with any temp files left behind by processes
+    /// which failed to finish writing an entry.
+    pub fn clear(&self) -> io::Result<()> {
+      let entries = match std_fs::read_dir(&self.dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+      };
+      for entry in entries {
+        let path = entry?.path();
+        let is_temp = path
+          .file_name()
+          .is_some_and(|name| name.as_bytes().starts_with(Self::TEMP_PREFIX.as_bytes()));
+        if is_temp || path.extension() == Some(OsStr::new(Self::EXTENSION)) {
+          match std_fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+          }
+        }
+      }
+      Ok(())
+    }
+  }
+
+  /// The state of a shell after running an [`EnvAfterScript`], which can be replayed into later
+  /// scripts.
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::ffi::OsStr;
+  /// use super_process::{sh, exe, base::CommandBase, sync::SyncInvocable};
+  ///
+  /// let env = sh::EnvAfterScript {
+  ///   source: sh::ShellSource {
+  ///     contents: br#"
+  ///       greet() {
+  ///         echo "hello $1"
+  ///       }
+  ///       alias hi='greet there'
+  ///       export GREETING=1
+  ///     "#.to_vec(),
+  ///   },
+  ///   interpreter: sh::Interpreter::bash(),
+  ///   ..Default::default()
+  /// };
+  /// let state = env.extract_shell_state().await.unwrap();
+  /// assert!(state.env.added.contains_key(OsStr::new("GREETING")));
+  ///
+  /// // Define the same functions and aliases before running another script.
+  /// let mut contents = state.prelude().contents;
+  /// contents.extend_from_slice(b"greet world\nhi\n");
+  /// let mut script = sh::ShellSource { contents }.into_script().await.unwrap();
+  /// script.interpreter = sh::Interpreter::bash();
+  /// let command = script.with_command(exe::Command::default()).setup_command().await.unwrap();
+  /// let output = command.invoke().await.unwrap();
+  /// assert_eq!(b"hello world\nhello there\n".as_ref(), &output.stdout);
+  /// # }) // async
+  ///```
+  #[derive(Debug, Clone, Default)]
+  pub struct ShellState {
+    /// The changes made to the environment. Apply these to a command with
+    /// [`exe::Command::env`].
+    pub env: exe::EnvDiff,
+    /// The definitions of every shell function, as printed by `declare -f` or `typeset -f`.
+    pub functions: Vec<u8>,
+    /// The definitions of every alias, as printed by `alias -p` or `alias -L`.
+    pub aliases: Vec<u8>,
+  }
+
+  impl ShellState {
+    /// Generate a script which defines the captured functions and aliases.
+    ///
+    /// Under `bash`, this also enables alias expansion, which is otherwise disabled in
+    /// non-interactive shells.
+    pub fn prelude(&self) -> ShellSource {
+      let mut contents: Vec<u8> = Vec::new();
+      contents.extend_from_slice(&self.functions);
+      contents.extend_from_slice(b"\n");
+      if !self.aliases.is_empty() {
+        contents.extend_from_slice(b"[ -n \"${BASH_VERSION-}\" ] && shopt -s expand_aliases\n");
+        contents.extend_from_slice(&self.aliases);
+        contents.extend_from_slice(b"\n");
+      }
+      ShellSource { contents }
+    }
+  }
+
+  type EnvMap = IndexMap<OsString, OsString>;
+
+  /// Split the output of `env -0` followed by an empty entry from the rest of `output`.
+  fn split_env_section(output: &[u8]) -> Result<(&[u8], &[u8]), ShellError> {
+    if let Some(rest) = output.strip_prefix(b"\0") {
+      return Ok((&output[..0], rest));
+    }
+    let separator = output
+      .windows(2)
+      .position(|w| w == b"\0\0")
+      .ok_or(ShellError::MissingSection)?;
+    Ok((&output[..separator + 1], &output[separator + 2..]))
+  }
+
+  /// Split the output of `env -0`, an empty entry, then `env -0` again, into two snapshots.
+  fn parse_env_snapshots(output: &[u8]) -> Result<(EnvMap, EnvMap), ShellError> {
+    let (before, after) = split_env_section(output)?;
+    Ok((parse_env(before)?, parse_env(after)?))
+  }
+
+  /// Parse two env snapshots followed by function and alias definitions.
+  fn parse_shell_state(output: &[u8]) -> Result<ShellState, ShellError> {
+    let (before, rest) = split_env_section(output)?;
+    let (after, rest) = split_env_section(rest)?;
+    let separator = rest
+      .iter()
+      .position(|b| *b == 0)
+      .ok_or(ShellError::MissingSection)?;
+    Ok(ShellState {
+      env: exe::EnvDiff::between(&parse_env(before)?, &parse_env(after)?),
+      functions: rest[..separator].to_vec(),
+      aliases: rest[separator + 1..].to_vec(),
+    })
+  }
 
-      Ok(exe::EnvModifications(env_map))
+  /// Parse the output of `env -0` into key-value pairs.
+  fn parse_env(output: &[u8]) -> Result<EnvMap, ShellError> {
+    let mut entries: Vec<&[u8]> = output.split(|b| *b == 0).collect();
+    /* Every entry is terminated by a NUL, so the last piece is empty unless the output was
+     * truncated. */
+    match entries.pop() {
+      Some([]) => (),
+      Some(partial) => {
+        return Err(ShellError::EnvEntry(
+          OsStr::from_bytes(partial).to_os_string(),
+        ))
+      },
+      None => unreachable!("split always produces at least one piece"),
+    }
+    let mut env_map: EnvMap = IndexMap::new();
+    for entry in entries {
+      match entry.iter().position(|b| *b == b'=') {
+        Some(equals_index) if equals_index > 0 => {
+          let key = OsStr::from_bytes(&entry[..equals_index]);
+          let value = OsStr::from_bytes(&entry[equals_index + 1..]);
+          env_map.insert(key.to_os_string(), value.to_os_string());
+        },
+        _ => {
+          return Err(ShellError::EnvEntry(
+            OsStr::from_bytes(entry).to_os_string(),
+          ))
+        },
+      }
     }
+    Ok(env_map)
   }
 
   /// Execute a command line beginning with this shell script.
   ///
-  /// The later arguments provided via [`Self::with_command`] (FIXME!)
+  /// The script sees its own path as `$0`. With [`Self::with_command`], the positional
+  /// parameters `"$@"` are the entire base command line, starting with its executable, so that
+  /// the script can wrap the command with `exec "$@"`. With [`Self::with_args`], the positional
+  /// parameters are exactly the given arguments.
   ///```
   /// # tokio_test::block_on(async {
   /// use std::io::Write;
@@ -957,7 +5779,11 @@ pub mod sh {
   ///   script_path.keep().unwrap()
   /// };
   /// let script_path = exe::Exe(fs::File(script_path));
-  /// let script = sh::ShellScript { script_path };
+  /// let script = sh::ShellScript {
+  ///   script_path,
+  ///   temp_file: None,
+  ///   interpreter: sh::Interpreter::default(),
+  /// };
   /// let command = script.with_command(exe::Command::default())
   ///   .setup_command().await.unwrap();
   ///
@@ -965,17 +5791,93 @@ pub mod sh {
   /// assert!(b"hey\n".as_ref() == &output.stdout);
   /// # }) // async
   ///```
+  ///
+  /// The positional parameters are set according to the invocation:
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::path::PathBuf;
+  /// use super_process::{sh, exe, sync::SyncInvocable, base::CommandBase, fs};
+  ///
+  /// let source = sh::ShellSource {
+  ///   contents: br#"echo "$0"; echo "$#"; for arg in "$@"; do echo "<$arg>"; done"#.to_vec(),
+  /// };
+  /// let script = source.into_script().await.unwrap();
+  /// let script_path = script.script_path.clone().0.0;
+  ///
+  /// let base = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("echo"))),
+  ///   argv: ["a b", "c"].as_ref().into(),
+  ///   ..Default::default()
+  /// };
+  /// let command = script.clone().with_command(base).setup_command().await.unwrap();
+  /// let output = command.invoke().await.unwrap().decode(exe::Command::default()).unwrap();
+  /// assert_eq!(format!("{}\n3\n<echo>\n<a b>\n<c>\n", script_path.display()), output.stdout);
+  ///
+  /// let command = script.with_args(["a b", "c"].as_ref().into()).setup_command().await.unwrap();
+  /// let output = command.invoke().await.unwrap().decode(exe::Command::default()).unwrap();
+  /// assert_eq!(format!("{}\n2\n<a b>\n<c>\n", script_path.display()), output.stdout);
+  /// # }) // async
+  ///```
   #[derive(Debug, Clone)]
   pub struct ShellScript {
     /// The script to execute.
     pub script_path: exe::Exe,
+    /// The temp file at [`Self::script_path`], if it should be deleted after use.
+    pub temp_file: Option<Arc<TempPath>>,
+    /// The shell to execute the script with.
+    pub interpreter: Interpreter,
   }
 
   impl ShellScript {
-    /// Provide a command line for this shell script to execute.
+    /// Keep the temp file for this script instead of deleting it, e.g. for debugging.
+    ///
+    /// This fails if the temp file is shared with a clone of this script, or with a command it
+    /// was provided to.
+    ///```
+    /// # tokio_test::block_on(async {
+    /// use super_process::sh;
+    ///
+    /// let source = sh::ShellSource { contents: b"echo hey".to_vec() };
+    /// let script = source.into_script().await.unwrap().keep().unwrap();
+    /// let script_path = script.script_path.clone().0.0;
+    /// drop(script);
+    /// assert!(script_path.exists());
+    /// std::fs::remove_file(script_path).unwrap();
+    /// # }) // async
+    ///```
+    pub fn keep(self) -> Result<Self, ShellError> {
+      let Self {
+        script_path,
+        temp_file,
+        interpreter,
+      } = self;
+      if let Some(temp_file) = temp_file {
+        let temp_file = Arc::try_unwrap(temp_file)
+          .map_err(|_| io::Error::other("the script's temp file is shared with another handle"))?;
+        temp_file.keep().map_err(io::Error::from)?;
+      }
+      Ok(Self {
+        script_path,
+        temp_file: None,
+        interpreter,
+      })
+    }
+
+    /// Provide a command line for this shell script to execute, which becomes `"$@"`.
     pub fn with_command(self, base: exe::Command) -> ShellScriptInvocation {
       ShellScriptInvocation { script: self, base }
     }
+
+    /// Provide the positional parameters `"$@"` for this shell script.
+    ///
+    /// Other configuration such as the working directory can then be set on
+    /// [`ShellScriptInvocation::base`].
+    pub fn with_args(self, argv: exe::Argv) -> ShellScriptInvocation {
+      self.with_command(exe::Command {
+        argv,
+        ..Default::default()
+      })
+    }
   }
 
   /// The command wrapper for a shell script.
@@ -983,7 +5885,8 @@ pub mod sh {
   pub struct ShellScriptInvocation {
     /// The script to preface the command line with.
     pub script: ShellScript,
-    /// The command line to provide to the script.
+    /// The command line to provide to the script. If [`exe::Command::exe`] is empty, only
+    /// [`exe::Command::argv`] is provided.
     pub base: exe::Command,
   }
 
@@ -991,10 +5894,192 @@ pub mod sh {
   impl CommandBase for ShellScriptInvocation {
     async fn setup_command(self) -> Result<exe::Command, base::SetupError> {
       let Self {
-        script: ShellScript { script_path },
+        script:
+          ShellScript {
+            script_path,
+            temp_file,
+            interpreter,
+          },
+        mut base,
+      } = self;
+      base.unshift_shell_script(interpreter, vec![script_path
+        .into_path_buf()
+        .into_os_string()]);
+      base.temp_files.extend(temp_file);
+      Ok(base)
+    }
+  }
+
+  /// How an [`InlineScript`] passes its source to the interpreter.
+  #[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+  pub enum InlineMode {
+    /// Use [`Self::Argument`] if the script fits in a single argument, and the whole command line
+    /// along with the environment fits within `ARG_MAX`. Otherwise use [`Self::Stdin`] if the
+    /// command's stdin is unused, otherwise write a temp file as with
+    /// [`ShellSource::into_script`].
+    #[default]
+    Auto,
+    /// Pass the script as the argument to `-c`. Scripts must not contain NUL bytes, and are
+    /// limited to 128KiB on Linux.
+    Argument,
+    /// Pass `-s` and write the script to the interpreter's stdin. Commands in the script then
+    /// cannot read from stdin, so this requires [`exe::Command::stdin`] to be unset. This cannot
+    /// be used with [`PtyStreamable::invoke_pty`](crate::pty::PtyStreamable::invoke_pty), which
+    /// connects stdin to the terminal.
+    Stdin,
+  }
+
+  /// Execute a shell script without writing it to a file.
+  ///
+  /// In every mode, `$0` is the interpreter. The positional parameters are set as for
+  /// [`ShellScript`].
+  ///```
+  /// # tokio_test::block_on(async {
+  /// use std::{ffi::OsString, path::PathBuf};
+  /// use super_process::{sh, exe, fs, base::CommandBase, sync::SyncInvocable};
+  ///
+  /// let base = exe::Command {
+  ///   exe: exe::Exe(fs::File(PathBuf::from("first"))),
+  ///   argv: ["second"].as_ref().into(),
+  ///   ..Default::default()
+  /// };
+  /// for mode in [sh::InlineMode::Argument, sh::InlineMode::Stdin] {
+  ///   let script = sh::InlineScript {
+  ///     source: sh::ShellSource { contents: b"echo \"$#: $1 $2\"".to_vec() },
+  ///     mode,
+  ///     ..Default::default()
+  ///   };
+  ///   let command = script.with_command(base.clone()).setup_command().await.unwrap();
+  ///   let output = command.invoke().await.unwrap();
+  ///   assert_eq!(b"2: first second\n".as_ref(), &output.stdout);
+  /// }
+  ///
+  /// // Scripts which are too long for an argument are written to stdin instead.
+  /// let mut contents = vec![b'#'; 256 * 1024];
+  /// contents.extend_from_slice(b"\necho long");
+  /// let script = sh::InlineScript {
+  ///   source: sh::ShellSource { contents },
+  ///   ..Default::default()
+  /// };
+  /// let command = script.with_command(exe::Command::default()).setup_command().await.unwrap();
+  /// assert_eq!(Some(&OsString::from("-s")), command.argv.0.front());
+  /// let output = command.invoke().await.unwrap();
+  /// assert_eq!(b"long\n".as_ref(), &output.stdout);
+  ///
+  /// // The same happens if the environment leaves no room for the script on the command line.
+  /// let arg_max = unsafe { libc::sysconf(libc::_SC_ARG_MAX) } as usize;
+  /// let chunk = 32 * 1024;
+  /// let env = (0..(arg_max - 48 * 1024) / chunk)
+  ///   .map(|i| {
+  ///     exe::EnvOp::Set(format!("SUPER_PROCESS_FILL_{}", i).into(), "x".repeat(chunk).into())
+  ///   })
+  ///   .collect();
+  /// let mut contents = vec![b'#'; 64 * 1024];
+  /// contents.extend_from_slice(b"\necho crowded");
+  /// let script = sh::InlineScript {
+  ///   source: sh::ShellSource { contents },
+  ///   ..Default::default()
+  /// };
+  /// let base = exe::Command { env: exe::EnvModifications(env), ..Default::default() };
+  /// let command = script.with_command(base).setup_command().await.unwrap();
+  /// assert_eq!(Some(&OsString::from("-s")), command.argv.0.front());
+  /// let output = command.invoke().await.unwrap();
+  /// assert_eq!(b"crowded\n".as_ref(), &output.stdout);
+  /// # }) // async
+  ///```
+  #[derive(Debug, Clone, Default)]
+  pub struct InlineScript {
+    /// The script to execute.
+    pub source: ShellSource,
+    /// How to pass the script to the interpreter.
+    pub mode: InlineMode,
+    /// The shell to execute the script with.
+    pub interpreter: Interpreter,
+  }
+
+  impl InlineScript {
+    /// Provide a command line for this shell script to execute, which becomes `"$@"`.
+    pub fn with_command(self, base: exe::Command) -> InlineScriptInvocation {
+      InlineScriptInvocation { script: self, base }
+    }
+
+    /// Provide the positional parameters `"$@"` for this shell script.
+    pub fn with_args(self, argv: exe::Argv) -> InlineScriptInvocation {
+      self.with_command(exe::Command {
+        argv,
+        ..Default::default()
+      })
+    }
+  }
+
+  /// The command wrapper for an inline shell script.
+  #[derive(Debug, Clone)]
+  pub struct InlineScriptInvocation {
+    /// The script to preface the command line with.
+    pub script: InlineScript,
+    /// The command line to provide to the script.
+    pub base: exe::Command,
+  }
+
+  #[async_trait]
+  impl CommandBase for InlineScriptInvocation {
+    async fn setup_command(self) -> Result<exe::Command, base::SetupError> {
+      let Self {
+        script: InlineScript {
+          source,
+          mode,
+          interpreter,
+        },
         mut base,
       } = self;
-      base.unshift_shell_script(script_path);
+      let stdin_available = base.stdin.is_none();
+      let mode = match mode {
+        InlineMode::Auto if source.fits_in_command(&interpreter, &base) => InlineMode::Argument,
+        InlineMode::Auto if stdin_available => InlineMode::Stdin,
+        InlineMode::Auto => {
+          let script = ShellScript {
+            interpreter,
+            ..source.write_script()?
+          };
+          return script.with_command(base).setup_command().await;
+        },
+        mode => mode,
+      };
+      match mode {
+        InlineMode::Argument => {
+          if !source.fits_in_argument() {
+            return Err(
+              io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "script contains a NUL byte or is too long for a single argument",
+              )
+              .into(),
+            );
+          }
+          let zeroth = interpreter.exe.clone().into_path_buf().into_os_string();
+          let ShellSource { contents } = source;
+          base.unshift_shell_script(interpreter, vec![
+            "-c".into(),
+            OsString::from_vec(contents),
+            zeroth,
+          ]);
+        },
+        InlineMode::Stdin => {
+          if !stdin_available {
+            return Err(
+              io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot write script to stdin, which is already configured for the command",
+              )
+              .into(),
+            );
+          }
+          let ShellSource { contents } = source;
+          base.stdin = Some(exe::Stdin::Bytes(contents));
+          base.unshift_shell_script(interpreter, vec!["-s".into()]);
+        },
+        InlineMode::Auto => unreachable!("auto mode was resolved above"),
+      }
       Ok(base)
     }
   }